@@ -19,11 +19,15 @@ fn try_main() -> Result<()> {
     {
         let _s = Section::new("BUILD");
         cmd!("cargo test --workspace --no-run").run()?;
+        cmd!("cargo test --no-default-features --no-run").run()?;
+        cmd!("cargo test --features extra-cmd,extra-fs --no-run").run()?;
     }
 
     {
         let _s = Section::new("TEST");
         cmd!("cargo test --workspace").run()?;
+        cmd!("cargo test --no-default-features").run()?;
+        cmd!("cargo test --features extra-cmd,extra-fs").run()?;
     }
 
     {
@@ -36,7 +40,7 @@ fn try_main() -> Result<()> {
                 match words.as_slice() {
                     [n, "=", v, ..] if n.trim() == "version" => {
                         assert!(v.starts_with('"') && v.ends_with('"'));
-                        return Some(&v[1..v.len() - 1]);
+                        Some(&v[1..v.len() - 1])
                     }
                     _ => None,
                 }
@@ -0,0 +1,85 @@
+//! Customizing how commands are echoed
+use std::{
+    cell::Cell,
+    io::{self, Write},
+    sync::{Arc, Mutex},
+};
+
+use crate::{gsl, Cmd, EchoTarget};
+
+type Hook = Arc<dyn Fn(&Cmd) + Send + Sync>;
+
+/// Echoes commands with `hook` instead of printing them, for example to
+/// forward them to a logger.
+///
+/// [`Cmd::run`] and friends call the hook with every command they would echo,
+/// that is, unless the command is [`quiet`](Cmd::quiet), once per attempt
+/// of a [`retry`](Cmd::retry). The hook is global, installing one replaces
+/// the previous one. Pass [`default_echo_hook`] to restore printing.
+///
+/// ```
+/// use xshell::{cmd, default_echo_hook, set_echo_hook};
+///
+/// set_echo_hook(|cmd| eprintln!("[xshell] {}", cmd));
+/// cmd!("echo hello").run()?;
+/// set_echo_hook(default_echo_hook);
+/// # Ok::<(), xshell::Error>(())
+/// ```
+pub fn set_echo_hook(hook: impl Fn(&Cmd) + Send + Sync + 'static) {
+    let _guard = gsl::write();
+    *hook_slot().lock().unwrap_or_else(|err| err.into_inner()) = Some(Arc::new(hook));
+}
+
+/// Prints `$ cmd` to stdout, or to the stream set with [`Cmd::echo_to`].
+/// This is how commands are echoed unless [`set_echo_hook`] is used.
+pub fn default_echo_hook(cmd: &Cmd) {
+    let prefix = cmd.log_prefix.as_deref().unwrap_or("");
+    let suffix = match cmd.retry {
+        Some((attempts, _delay)) if ATTEMPT.with(|it| it.get()) > 1 => {
+            format!(" (attempt {}/{})", ATTEMPT.with(|it| it.get()), attempts)
+        }
+        _ => String::new(),
+    };
+    match cmd.echo {
+        EchoTarget::Stdout => println!("{}$ {}{}", prefix, cmd, suffix),
+        EchoTarget::Stderr => eprintln!("{}$ {}{}", prefix, cmd, suffix),
+        EchoTarget::None => (),
+    }
+}
+
+/// Echoes the `attempt`th run of `cmd`.
+pub(crate) fn echo(cmd: &Cmd, attempt: u32) {
+    if cmd.echo != EchoTarget::None {
+        // Cloned, so that the hook can run commands itself.
+        let hook = hook_slot().lock().unwrap_or_else(|err| err.into_inner()).clone();
+        ATTEMPT.with(|it| it.set(attempt));
+        match hook {
+            Some(hook) => hook(cmd),
+            None => default_echo_hook(cmd),
+        }
+        ATTEMPT.with(|it| it.set(1));
+    }
+    // The child writes directly to the file descriptors, make sure that
+    // everything we've printed so far lands before its output, even if the
+    // command itself isn't echoed.
+    let _ = io::stdout().flush();
+    let _ = io::stderr().flush();
+}
+
+/// Echoes changing to `dir` before running `cmd` there, as a `cd` command
+/// which is echoed like `cmd` is.
+#[cfg(feature = "extra-cmd")]
+pub(crate) fn echo_cd(cmd: &Cmd, dir: &std::path::Path) {
+    let mut cd = Cmd::new("cd").arg(dir).echo_to(cmd.echo);
+    cd.log_prefix = cmd.log_prefix.clone();
+    echo(&cd, 1);
+}
+
+thread_local! {
+    /// The attempt being echoed, for [`default_echo_hook`].
+    static ATTEMPT: Cell<u32> = const { Cell::new(1) };
+}
+
+fn hook_slot() -> &'static Mutex<Option<Hook>> {
+    global!(Mutex<Option<Hook>>, Mutex::new(None))
+}
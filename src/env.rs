@@ -1,56 +1,345 @@
+#[cfg(feature = "extra-fs")]
+use std::io;
 use std::{
     ffi::{OsStr, OsString},
     path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
 };
 
+#[cfg(feature = "extra-fs")]
+use crate::glob;
 use crate::{cwd, error::fs_err, gsl, Result};
 
 pub fn pushd(dir: impl AsRef<Path>) -> Result<Pushd> {
     Pushd::new(dir.as_ref())
 }
 
+/// Like [`pushd`], but enters the single directory matching `pattern`.
+///
+/// Useful for directories with unpredictable names, like
+/// `target/package/foo-*`, see [`glob`] for the pattern syntax. Errors if
+/// the pattern matches no directories or more than one.
+///
+/// Requires the `extra-fs` feature.
+#[cfg(feature = "extra-fs")]
+pub fn pushd_glob(pattern: &str) -> Result<Pushd> {
+    let mut dirs = glob(pattern)?;
+    dirs.retain(|it| it.is_dir());
+    if dirs.len() != 1 {
+        let msg = if dirs.is_empty() {
+            "pattern matched no directories".to_string()
+        } else {
+            let dirs = dirs.iter().map(|it| it.display().to_string()).collect::<Vec<_>>();
+            format!("pattern matched more than one directory: {}", dirs.join(", "))
+        };
+        return Err(fs_err(pattern.into(), io::Error::new(io::ErrorKind::Other, msg)));
+    }
+    Pushd::new(&dirs[0])
+}
+
+/// Changes the current directory for the rest of the program.
+///
+/// Prefer [`pushd`], which restores the previous directory when done. An
+/// active [`Pushd`] guard still returns to the directory it was created in,
+/// so a `cd` inside its scope is undone when the guard is dropped.
+pub fn cd(dir: impl AsRef<Path>) -> Result<()> {
+    let _guard = gsl::write();
+    set_current_dir(dir.as_ref())?;
+    CD_EPOCH.fetch_add(1, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Incremented by every [`cd`], so that [`Pushd`] can tell a deliberate
+/// directory change from a concurrent one.
+static CD_EPOCH: AtomicUsize = AtomicUsize::new(0);
+
 #[must_use]
 pub struct Pushd {
     _guard: gsl::Guard,
     prev_dir: PathBuf,
     dir: PathBuf,
+    cd_epoch: usize,
+    restored: bool,
+}
+
+/// Returns the value of the environment variable `key`, or `default` if it is
+/// not set.
+///
+/// Non-unicode values are converted lossily, so the result can always be
+/// passed to [`Cmd::arg`](crate::Cmd::arg) or interpolated into [`cmd!`](crate::cmd).
+///
+/// ```
+/// use xshell::{cmd, env_or};
+///
+/// # #[cfg(feature = "macros")] {
+/// let profile = env_or("XSHELL_DOC_PROFILE", "release");
+/// assert_eq!(
+///     cmd!("cargo build --profile {profile}").to_string(),
+///     "cargo build --profile release",
+/// );
+/// # }
+/// ```
+pub fn env_or(key: impl AsRef<OsStr>, default: impl Into<String>) -> String {
+    _env_or(key.as_ref(), default.into())
+}
+fn _env_or(key: &OsStr, default: String) -> String {
+    let _guard = gsl::read();
+    match std::env::var_os(key) {
+        Some(value) => value.to_string_lossy().into_owned(),
+        None => default,
+    }
+}
+
+/// Returns the number of logical CPUs available to the process, suitable as a
+/// default job limit.
+///
+/// Falls back to `1` if the number can't be determined.
+pub fn num_cpus() -> usize {
+    std::thread::available_parallelism().map_or(1, |it| it.get())
+}
+
+/// Returns the entries of the `PATH` environment variable.
+///
+/// Requires the `extra-fs` feature.
+#[cfg(feature = "extra-fs")]
+pub fn path_dirs() -> Vec<PathBuf> {
+    let _guard = gsl::read();
+    match std::env::var_os("PATH") {
+        Some(path) => std::env::split_paths(&path).collect(),
+        None => Vec::new(),
+    }
+}
+
+/// Finds `program` in the `PATH`, like `which`, and returns its absolute
+/// path.
+///
+/// On Windows, the extensions from `PATHEXT` are tried as well. A `program`
+/// with a directory in it, like `./x.sh`, is checked as is, relative to the
+/// current directory.
+///
+/// ```
+/// # use xshell::which;
+/// if which("cargo-clippy").is_none() {
+///     eprintln!("clippy is not installed, run `rustup component add clippy`");
+/// }
+/// ```
+///
+/// Requires the `extra-fs` feature.
+#[cfg(feature = "extra-fs")]
+pub fn which(program: impl AsRef<OsStr>) -> Option<PathBuf> {
+    _which(program.as_ref())
+}
+#[cfg(feature = "extra-fs")]
+fn _which(program: &OsStr) -> Option<PathBuf> {
+    let _guard = gsl::read();
+    let program = Path::new(program);
+    let path = if program.components().count() > 1 {
+        find_executable(program)?
+    } else {
+        // Not `path_dirs`, which would take the lock again.
+        let path = std::env::var_os("PATH").unwrap_or_default();
+        let mut dirs = std::env::split_paths(&path);
+        dirs.find_map(|dir| {
+            if dir.as_os_str().is_empty() {
+                return None;
+            }
+            find_executable(&dir.join(program))
+        })?
+    };
+    if path.is_absolute() {
+        Some(path)
+    } else {
+        std::env::current_dir().ok().map(|cwd| cwd.join(path))
+    }
+}
+
+/// Returns `path` if it's executable, or, on Windows, the first executable
+/// `path` with an extension from `PATHEXT`.
+#[cfg(feature = "extra-fs")]
+fn find_executable(path: &Path) -> Option<PathBuf> {
+    if is_executable(path) {
+        return Some(path.to_path_buf());
+    }
+    #[cfg(windows)]
+    {
+        let pathext = std::env::var("PATHEXT").unwrap_or_else(|_| ".COM;.EXE;.BAT;.CMD".into());
+        for ext in pathext.split(';') {
+            let mut res = path.as_os_str().to_owned();
+            res.push(ext);
+            if is_executable(Path::new(&res)) {
+                return Some(res.into());
+            }
+        }
+    }
+    None
+}
+
+#[cfg(feature = "extra-fs")]
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::metadata(path).map_or(false, |it| it.is_file() && it.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(feature = "extra-fs")]
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+/// Joins `dirs` into a value for the `PATH` environment variable, using the
+/// platform's separator.
+///
+/// Errors if one of the directories contains the separator itself.
+///
+/// ```
+/// use xshell::{join_path_dirs, path_dirs, pushenv};
+///
+/// let mut dirs = path_dirs();
+/// dirs.insert(0, "./bin".into());
+/// let _env = pushenv("PATH", join_path_dirs(&dirs)?);
+/// # Ok::<(), xshell::Error>(())
+/// ```
+///
+/// Requires the `extra-fs` feature.
+#[cfg(feature = "extra-fs")]
+pub fn join_path_dirs(dirs: &[PathBuf]) -> Result<OsString> {
+    if let Some(dir) =
+        dirs.iter().find(|dir| std::env::join_paths(std::slice::from_ref(*dir)).is_err())
+    {
+        let msg = "path contains the `PATH` separator";
+        return Err(fs_err(dir.clone(), io::Error::new(io::ErrorKind::InvalidInput, msg)));
+    }
+    Ok(std::env::join_paths(dirs).unwrap())
+}
+
+/// Returns whether stdout is a terminal, to decide whether to print colors
+/// or progress bars.
+pub fn is_tty_stdout() -> bool {
+    is_tty(1)
+}
+
+/// Returns whether stderr is a terminal.
+pub fn is_tty_stderr() -> bool {
+    is_tty(2)
+}
+
+#[cfg(unix)]
+fn is_tty(fd: i32) -> bool {
+    extern "C" {
+        fn isatty(fd: i32) -> i32;
+    }
+    unsafe { isatty(fd) == 1 }
+}
+
+#[cfg(windows)]
+fn is_tty(fd: i32) -> bool {
+    extern "system" {
+        fn GetStdHandle(std_handle: u32) -> *mut std::ffi::c_void;
+        fn GetConsoleMode(console_handle: *mut std::ffi::c_void, mode: *mut u32) -> i32;
+    }
+    const STD_OUTPUT_HANDLE: u32 = -11i32 as u32;
+    const STD_ERROR_HANDLE: u32 = -12i32 as u32;
+    let std_handle = if fd == 1 { STD_OUTPUT_HANDLE } else { STD_ERROR_HANDLE };
+    let mut mode = 0;
+    unsafe { GetConsoleMode(GetStdHandle(std_handle), &mut mode) != 0 }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn is_tty(_fd: i32) -> bool {
+    false
 }
 
 pub fn pushenv(k: impl AsRef<OsStr>, v: impl AsRef<OsStr>) -> Pushenv {
     Pushenv::new(k.as_ref(), v.as_ref())
 }
 
+/// Like [`pushenv`], but also returns the previous value of the variable.
+///
+/// The value is read while holding the lock, so it can't race with the set.
+pub fn pushenv_prev(k: impl AsRef<OsStr>, v: impl AsRef<OsStr>) -> (Pushenv, Option<OsString>) {
+    let pushenv = Pushenv::new(k.as_ref(), v.as_ref());
+    let prev_value = pushenv.prev_value.clone();
+    (pushenv, prev_value)
+}
+
+/// Unsets the environment variable `key` until the returned guard is
+/// dropped.
+///
+/// ```
+/// use xshell::{pushenv, pushenv_remove};
+///
+/// let _e = pushenv("XSHELL_DOC_FLAGS", "-Dwarnings");
+/// {
+///     let _e = pushenv_remove("XSHELL_DOC_FLAGS");
+///     assert!(std::env::var_os("XSHELL_DOC_FLAGS").is_none());
+/// }
+/// assert_eq!(std::env::var("XSHELL_DOC_FLAGS").as_deref(), Ok("-Dwarnings"));
+/// ```
+pub fn pushenv_remove(k: impl AsRef<OsStr>) -> Pushenv {
+    Pushenv::_new(k.as_ref(), None)
+}
+
 #[must_use]
 pub struct Pushenv {
     _guard: gsl::Guard,
     key: OsString,
     prev_value: Option<OsString>,
-    value: OsString,
+    /// `None` if the variable was removed.
+    value: Option<OsString>,
 }
 
 impl Pushd {
     fn new(dir: &Path) -> Result<Pushd> {
         let guard = gsl::write();
         let prev_dir = cwd()?;
-        set_current_dir(&dir)?;
+        set_current_dir(dir)?;
         let dir = cwd()?;
-        Ok(Pushd { _guard: guard, prev_dir, dir })
+        let cd_epoch = CD_EPOCH.load(Ordering::SeqCst);
+        Ok(Pushd { _guard: guard, prev_dir, dir, cd_epoch, restored: false })
+    }
+
+    /// Restores the previous directory now, returning the error if that
+    /// fails.
+    ///
+    /// Dropping the guard does the same, but can only print the error.
+    pub fn into_result(mut self) -> Result<()> {
+        self.restore()
+    }
+
+    fn restore(&mut self) -> Result<()> {
+        self.restored = true;
+        // Don't turn a panic into an abort with a second one.
+        if self.cd_epoch == CD_EPOCH.load(Ordering::SeqCst) && !std::thread::panicking() {
+            match cwd() {
+                Ok(dir) => assert_eq!(
+                    dir,
+                    self.dir,
+                    "current directory was changed concurrently.
+expected {}
+got      {}",
+                    self.dir.display(),
+                    dir.display()
+                ),
+                // The directory was removed from under us.
+                Err(_) if !self.dir.exists() => (),
+                Err(err) => return Err(err),
+            }
+        }
+        set_current_dir(&self.prev_dir)
     }
 }
 
 impl Drop for Pushd {
     fn drop(&mut self) {
-        let dir = cwd().unwrap();
-        assert_eq!(
-            dir,
-            self.dir,
-            "current directory was changed concurrently.
-expected {}
-got      {}",
-            self.dir.display(),
-            dir.display()
-        );
-        set_current_dir(&self.prev_dir).unwrap()
+        if self.restored {
+            return;
+        }
+        // If the previous directory was removed as well, an outer `Pushd`
+        // restores its own.
+        if let Err(err) = self.restore() {
+            eprintln!("xshell: failed to restore the current directory: {}", err);
+        }
     }
 }
 
@@ -60,29 +349,36 @@ fn set_current_dir(path: &Path) -> Result<()> {
 
 impl Pushenv {
     fn new(key: &OsStr, value: &OsStr) -> Pushenv {
+        Pushenv::_new(key, Some(value))
+    }
+    fn _new(key: &OsStr, value: Option<&OsStr>) -> Pushenv {
         let guard = gsl::write();
         let prev_value = std::env::var_os(key);
-        std::env::set_var(key, value);
-        Pushenv { _guard: guard, key: key.to_os_string(), prev_value, value: value.to_os_string() }
+        match value {
+            Some(value) => std::env::set_var(key, value),
+            None => std::env::remove_var(key),
+        }
+        let value = value.map(|it| it.to_os_string());
+        Pushenv { _guard: guard, key: key.to_os_string(), prev_value, value }
     }
 }
 
 impl Drop for Pushenv {
     fn drop(&mut self) {
         let value = std::env::var_os(&self.key);
-        assert_eq!(
-            value.as_ref(),
-            Some(&self.value),
-            "environmental variable was changed concurrently.
+        // Don't turn a panic into an abort with a second one.
+        if !std::thread::panicking() {
+            assert_eq!(
+                value, self.value,
+                "environmental variable was changed concurrently.
 var      {:?}
 expected {:?}
 got      {:?}",
-            self.key,
-            self.value,
-            value
-        );
+                self.key, self.value, value
+            );
+        }
         match &self.prev_value {
-            Some(it) => std::env::set_var(&self.key, &it),
+            Some(it) => std::env::set_var(&self.key, it),
             None => std::env::remove_var(&self.key),
         }
     }
@@ -1,4 +1,4 @@
-use std::{fmt, io, path::PathBuf, process::ExitStatus, string::FromUtf8Error};
+use std::{fmt, io, path::PathBuf, process::ExitStatus, string::FromUtf8Error, time::Duration};
 
 use crate::Cmd;
 
@@ -8,9 +8,13 @@ pub struct Error {
     repr: Box<Repr>,
 }
 
+// Boxed as a whole in `Error`.
+#[allow(clippy::large_enum_variant, clippy::enum_variant_names)]
 enum Repr {
     CmdError(CmdError),
     FsError(FsError),
+    #[cfg(feature = "extra-cmd")]
+    CmdLineError(CmdLineError),
 }
 
 impl fmt::Display for Error {
@@ -24,32 +28,226 @@ impl fmt::Display for Error {
         }
         match &*self.repr {
             Repr::CmdError(err) => match &err.kind {
-                CmdErrorKind::NonZeroStatus(status) => {
-                    write!(f, "command `{}` failed, {}", err.cmd, status)
+                CmdErrorKind::NonZeroStatus(status) => write_status(f, &err.cmd, status),
+                CmdErrorKind::PipelineStage { index, program, status } => {
+                    write!(f, "pipeline failed at stage {} (`{}`): ", index + 1, program)?;
+                    match status.code() {
+                        Some(code) => write!(f, "exit code {}", code),
+                        None => match signal_name(status) {
+                            Some((signal, name)) => {
+                                write!(f, "killed by signal {} ({})", signal, name)
+                            }
+                            None => write!(f, "{}", status),
+                        },
+                    }
                 }
-                CmdErrorKind::Io(io_err) => {
-                    if io_err.kind() == io::ErrorKind::NotFound {
-                        write!(f, "command not found: `{}`", err.cmd.args[0].to_string_lossy())
+                CmdErrorKind::PipelineSpawn { index, program, io_err } => {
+                    if is_program_not_found(io_err) {
+                        write!(
+                            f,
+                            "pipeline failed at stage {}: `{}` not found, \
+                             is it installed and on PATH?",
+                            index + 1,
+                            program
+                        )
                     } else {
-                        write!(f, "command `{}` failed, {}", err.cmd, errstr(io_err))
+                        write!(
+                            f,
+                            "pipeline failed at stage {} (`{}`): {}",
+                            index + 1,
+                            program,
+                            errstr(io_err)
+                        )
                     }
                 }
+                #[cfg(feature = "extra-cmd")]
+                CmdErrorKind::NonZeroStatusWithOutput { status, tail } => {
+                    write_status(f, &err.cmd, status)?;
+                    write!(f, ", output tail:\n{}", tail)
+                }
+                CmdErrorKind::Io(io_err) => {
+                    write!(f, "command `{}` failed, {}", err.cmd, errstr(io_err))?;
+                    match &err.cmd.current_dir {
+                        Some(dir) => write!(f, " (in `{}`)", dir.display()),
+                        None => Ok(()),
+                    }
+                }
+                CmdErrorKind::NotFound(_) => {
+                    write!(
+                        f,
+                        "command `{}` failed: `{}` not found, is it installed and on PATH?",
+                        err.cmd,
+                        err.cmd.args[0].to_string_lossy()
+                    )?;
+                    match &err.cmd.current_dir {
+                        Some(dir) => write!(f, " (in `{}`)", dir.display()),
+                        None => Ok(()),
+                    }
+                }
+                #[cfg(feature = "extra-cmd")]
+                CmdErrorKind::OutputMismatch { expected, actual } => {
+                    writeln!(f, "command `{}` produced unexpected output:", err.cmd)?;
+                    let mut expected = expected.lines();
+                    let mut actual = actual.lines();
+                    loop {
+                        match (expected.next(), actual.next()) {
+                            (None, None) => break,
+                            (Some(e), Some(a)) if e == a => writeln!(f, "  {}", e)?,
+                            (e, a) => {
+                                if let Some(e) = e {
+                                    writeln!(f, "- {}", e)?;
+                                }
+                                if let Some(a) = a {
+                                    writeln!(f, "+ {}", a)?;
+                                }
+                            }
+                        }
+                    }
+                    Ok(())
+                }
+                #[cfg(feature = "extra-cmd")]
+                CmdErrorKind::Parse { output, message } => {
+                    write!(
+                        f,
+                        "command `{}` failed, can't parse output {:?}: {}",
+                        err.cmd, output, message
+                    )
+                }
+                CmdErrorKind::EmptyProgram => {
+                    write!(f, "command `{}` failed, empty program name", err.cmd)
+                }
+                CmdErrorKind::Timeout(elapsed) => {
+                    write!(f, "command `{}` timed out after {:.1?}", err.cmd, elapsed)
+                }
                 CmdErrorKind::NonUtf8Stdout(utf8_err) => {
-                    write!(f, "command `{}` produced invalid utf8, {}", err.cmd, utf8_err)
+                    write!(
+                        f,
+                        "command `{}` produced invalid utf8, {}, stdout: {}",
+                        err.cmd,
+                        utf8_err,
+                        preview(utf8_err.as_bytes())
+                    )
+                }
+                CmdErrorKind::NonUtf8Stderr(utf8_err) => {
+                    write!(
+                        f,
+                        "command `{}` produced invalid utf8, {}, stderr: {}",
+                        err.cmd,
+                        utf8_err,
+                        preview(utf8_err.as_bytes())
+                    )
                 }
             },
-            Repr::FsError(err) => write!(f, "`{}`: {}", err.path.display(), errstr(&err.io_err)),
+            #[cfg(feature = "extra-cmd")]
+            Repr::CmdLineError(err) => {
+                write!(f, "can't parse command line `{}`: {}", err.line, err.message)
+            }
+            Repr::FsError(err) => {
+                write!(f, "`{}`", err.path.display())?;
+                if let Some(dst) = &err.dst {
+                    write!(f, " -> `{}`", dst.display())?;
+                }
+                write!(f, ": {}", errstr(&err.io_err))
+            }
         }
     }
 }
 
+fn write_status(f: &mut fmt::Formatter<'_>, cmd: &Cmd, status: &ExitStatus) -> fmt::Result {
+    match status.code() {
+        Some(code) => write!(f, "command `{}` failed, exit code: {}", cmd, code),
+        None => match signal_name(status) {
+            Some((signal, name)) => {
+                write!(f, "command `{}` failed, killed by signal {} ({})", cmd, signal, name)
+            }
+            None => write!(f, "command `{}` failed, {}", cmd, status),
+        },
+    }
+}
+
+#[cfg(unix)]
+fn signal_name(status: &ExitStatus) -> Option<(i32, &'static str)> {
+    use std::os::unix::process::ExitStatusExt;
+
+    let signal = status.signal()?;
+    let name = match signal {
+        1 => "SIGHUP",
+        2 => "SIGINT",
+        3 => "SIGQUIT",
+        4 => "SIGILL",
+        6 => "SIGABRT",
+        8 => "SIGFPE",
+        9 => "SIGKILL",
+        11 => "SIGSEGV",
+        13 => "SIGPIPE",
+        14 => "SIGALRM",
+        15 => "SIGTERM",
+        _ => return None,
+    };
+    Some((signal, name))
+}
+
+#[cfg(not(unix))]
+fn signal_name(_status: &ExitStatus) -> Option<(i32, &'static str)> {
+    None
+}
+
+/// Lossy, truncated rendering of a process output, for error messages.
+fn preview(bytes: &[u8]) -> String {
+    const MAX_LEN: usize = 64;
+    let truncated = bytes.len() > MAX_LEN;
+    let bytes = if truncated { &bytes[..MAX_LEN] } else { bytes };
+    let mut res = format!("{:?}", String::from_utf8_lossy(bytes));
+    if truncated {
+        res.push_str("...");
+    }
+    res
+}
+
 impl fmt::Debug for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         fmt::Display::fmt(self, f)
     }
 }
 
-impl std::error::Error for Error {}
+impl Error {
+    /// Returns the kind of the underlying [`io::Error`], if any.
+    ///
+    /// This allows telling, for example, a missing program or file apart from
+    /// a permission problem without matching on the message.
+    pub fn io_kind(&self) -> Option<io::ErrorKind> {
+        self.io_err().map(io::Error::kind)
+    }
+
+    fn io_err(&self) -> Option<&io::Error> {
+        match &*self.repr {
+            Repr::CmdError(err) => match &err.kind {
+                CmdErrorKind::Io(io_err)
+                | CmdErrorKind::NotFound(io_err)
+                | CmdErrorKind::PipelineSpawn { io_err, .. } => Some(io_err),
+                _ => None,
+            },
+            Repr::FsError(err) => Some(&err.io_err),
+            #[cfg(feature = "extra-cmd")]
+            Repr::CmdLineError(_) => None,
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        if let Some(io_err) = self.io_err() {
+            return Some(io_err);
+        }
+        match &*self.repr {
+            Repr::CmdError(CmdError {
+                kind: CmdErrorKind::NonUtf8Stdout(utf8_err) | CmdErrorKind::NonUtf8Stderr(utf8_err),
+                ..
+            }) => Some(utf8_err),
+            _ => None,
+        }
+    }
+}
 
 pub(crate) struct CmdError {
     cmd: Cmd,
@@ -58,21 +256,116 @@ pub(crate) struct CmdError {
 
 pub(crate) enum CmdErrorKind {
     NonZeroStatus(ExitStatus),
+    #[cfg(feature = "extra-cmd")]
+    NonZeroStatusWithOutput {
+        status: ExitStatus,
+        tail: String,
+    },
+    PipelineStage {
+        index: usize,
+        program: String,
+        status: ExitStatus,
+    },
+    PipelineSpawn {
+        index: usize,
+        program: String,
+        io_err: io::Error,
+    },
+    #[cfg(feature = "extra-cmd")]
+    Parse {
+        output: String,
+        message: String,
+    },
     Io(io::Error),
+    /// The program doesn't exist.
+    NotFound(io::Error),
     NonUtf8Stdout(FromUtf8Error),
+    NonUtf8Stderr(FromUtf8Error),
+    EmptyProgram,
+    Timeout(Duration),
+    #[cfg(feature = "extra-cmd")]
+    OutputMismatch {
+        expected: String,
+        actual: String,
+    },
 }
 
 impl CmdErrorKind {
     pub(crate) fn err(self, cmd: Cmd) -> Error {
-        Error { repr: Box::new(Repr::CmdError(CmdError { cmd, kind: self })) }
+        let kind = match self {
+            CmdErrorKind::Io(io_err) if is::<EmptyProgram>(&io_err) => CmdErrorKind::EmptyProgram,
+            CmdErrorKind::Io(io_err) if is_program_not_found(&io_err) => {
+                CmdErrorKind::NotFound(io_err)
+            }
+            CmdErrorKind::Io(io_err) if is::<Timeout>(&io_err) => {
+                let Timeout(elapsed) = *io_err.into_inner().unwrap().downcast().unwrap();
+                CmdErrorKind::Timeout(elapsed)
+            }
+            kind => kind,
+        };
+        Error { repr: Box::new(Repr::CmdError(CmdError { cmd, kind })) }
+    }
+}
+
+/// Spawn-time failures are `io::Error`s, so that they flow through the same
+/// paths as OS errors. Markers like this one are turned into dedicated
+/// `CmdErrorKind`s when the final error is constructed.
+#[derive(Debug)]
+pub(crate) struct EmptyProgram;
+
+impl fmt::Display for EmptyProgram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("empty program name")
     }
 }
 
+impl std::error::Error for EmptyProgram {}
+
+/// The command was killed because it ran longer than [`Cmd::timeout`].
+#[derive(Debug)]
+pub(crate) struct Timeout(pub(crate) Duration);
+
+impl fmt::Display for Timeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "timed out after {:.1?}", self.0)
+    }
+}
+
+impl std::error::Error for Timeout {}
+
+/// Errors with a payload come from us, like a missing file for a redirect,
+/// rather than from spawning the program.
+fn is_program_not_found(io_err: &io::Error) -> bool {
+    io_err.kind() == io::ErrorKind::NotFound && io_err.get_ref().is_none()
+}
+
+fn is<T: std::error::Error + 'static>(io_err: &io::Error) -> bool {
+    io_err.get_ref().map_or(false, |it| it.is::<T>())
+}
+
+#[cfg(feature = "extra-cmd")]
+pub(crate) struct CmdLineError {
+    line: String,
+    message: &'static str,
+}
+
+#[cfg(feature = "extra-cmd")]
+pub(crate) fn cmd_line_err(line: &str, message: &'static str) -> Error {
+    let line = line.to_string();
+    Error { repr: Box::new(Repr::CmdLineError(CmdLineError { line, message })) }
+}
+
 pub(crate) struct FsError {
     path: PathBuf,
+    dst: Option<PathBuf>,
     io_err: io::Error,
 }
 
 pub(crate) fn fs_err(path: PathBuf, io_err: io::Error) -> Error {
-    Error { repr: Box::new(Repr::FsError(FsError { path, io_err })) }
+    Error { repr: Box::new(Repr::FsError(FsError { path, dst: None, io_err })) }
+}
+
+/// Error for operations involving two paths, like linking or moving.
+pub(crate) fn fs_err2(src: PathBuf, dst: PathBuf, io_err: io::Error) -> Error {
+    Error { repr: Box::new(Repr::FsError(FsError { path: src, dst: Some(dst), io_err })) }
 }
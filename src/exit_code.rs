@@ -0,0 +1,44 @@
+//! Named exit codes, for [`Cmd::ok_statuses`](crate::Cmd::ok_statuses) and
+//! [`std::process::exit`].
+//!
+//! These are the BSD `sysexits.h` codes. They are a widely followed
+//! convention rather than a standard, so don't expect every tool to use them.
+//!
+//! ```
+//! # use xshell::{cmd, exit_code};
+//! cmd!("sh -c 'exit 75'").ok_statuses(&[exit_code::EX_TEMPFAIL]).run()?;
+//! # Ok::<(), xshell::Error>(())
+//! ```
+
+/// Successful termination.
+pub const EX_OK: i32 = 0;
+/// Command line usage error.
+pub const EX_USAGE: i32 = 64;
+/// Data format error.
+pub const EX_DATAERR: i32 = 65;
+/// Cannot open input.
+pub const EX_NOINPUT: i32 = 66;
+/// Addressee unknown.
+pub const EX_NOUSER: i32 = 67;
+/// Host name unknown.
+pub const EX_NOHOST: i32 = 68;
+/// Service unavailable.
+pub const EX_UNAVAILABLE: i32 = 69;
+/// Internal software error.
+pub const EX_SOFTWARE: i32 = 70;
+/// System error (e.g., can't fork).
+pub const EX_OSERR: i32 = 71;
+/// Critical OS file missing.
+pub const EX_OSFILE: i32 = 72;
+/// Can't create (user) output file.
+pub const EX_CANTCREAT: i32 = 73;
+/// Input/output error.
+pub const EX_IOERR: i32 = 74;
+/// Temporary failure, user is invited to retry.
+pub const EX_TEMPFAIL: i32 = 75;
+/// Remote error in protocol.
+pub const EX_PROTOCOL: i32 = 76;
+/// Permission denied.
+pub const EX_NOPERM: i32 = 77;
+/// Configuration error.
+pub const EX_CONFIG: i32 = 78;
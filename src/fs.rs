@@ -1,16 +1,48 @@
-use std::path::{Path, PathBuf};
+use std::{
+    fs::{File, OpenOptions},
+    path::{Component, Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
-use crate::{error::fs_err, gsl, Result};
+use crate::{
+    error::{fs_err, fs_err2},
+    gsl, Error, Result,
+};
 
+/// Removes a file, a symlink or a whole directory tree, like `rm -rf`.
+///
+/// Does nothing if `path` doesn't exist. Symlinks are removed, not followed.
 pub fn rm_rf(path: impl AsRef<Path>) -> Result<()> {
     _rm_rf(path.as_ref())
 }
 fn _rm_rf(path: &Path) -> Result<()> {
     let _guard = gsl::read();
-    if !path.exists() {
-        return Ok(());
+    match remove(path) {
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        res => with_path(path, res),
+    }
+}
+
+/// Like [`rm_rf`], but errors if `path` doesn't exist, to check that a
+/// cleanup actually removed something.
+pub fn rm_rf_strict(path: impl AsRef<Path>) -> Result<()> {
+    _rm_rf_strict(path.as_ref())
+}
+fn _rm_rf_strict(path: &Path) -> Result<()> {
+    let _guard = gsl::read();
+    with_path(path, remove(path))
+}
+
+fn remove(path: &Path) -> std::io::Result<()> {
+    let file_type = std::fs::symlink_metadata(path)?.file_type();
+    if file_type.is_dir() {
+        remove_dir_all(path)
+    } else if cfg!(windows) && file_type.is_symlink() && path.is_dir() {
+        // Directory symlinks are directories as far as Windows is concerned.
+        std::fs::remove_dir(path)
+    } else {
+        std::fs::remove_file(path)
     }
-    with_path(path, if path.is_file() { std::fs::remove_file(path) } else { remove_dir_all(path) })
 }
 
 pub fn read_file(path: impl AsRef<Path>) -> Result<String> {
@@ -21,6 +53,15 @@ fn _read_file(path: &Path) -> Result<String> {
     with_path(path, std::fs::read_to_string(path))
 }
 
+/// Like [`read_file`], but returns raw bytes, for files which aren't UTF-8.
+pub fn read_file_bytes(path: impl AsRef<Path>) -> Result<Vec<u8>> {
+    _read_file_bytes(path.as_ref())
+}
+fn _read_file_bytes(path: &Path) -> Result<Vec<u8>> {
+    let _guard = gsl::read();
+    with_path(path, std::fs::read(path))
+}
+
 pub fn write_file(path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> Result<()> {
     _write_file(path.as_ref(), contents.as_ref())
 }
@@ -29,6 +70,98 @@ fn _write_file(path: &Path, contents: &[u8]) -> Result<()> {
     with_path(path, std::fs::write(path, contents))
 }
 
+/// Read-only memory map of a file, returned by [`mmap_file`].
+#[cfg(feature = "mmap")]
+#[derive(Debug)]
+pub struct Mmap {
+    inner: memmap2::Mmap,
+}
+
+#[cfg(feature = "mmap")]
+impl std::ops::Deref for Mmap {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        &self.inner
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl AsRef<[u8]> for Mmap {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+/// Maps the file at `path` into memory, without reading it onto the heap.
+///
+/// Requires the `mmap` feature.
+///
+/// # Safety
+///
+/// The returned bytes alias the file on disk. If the file is modified or
+/// truncated while the map is alive, by this or any other process, the
+/// contents of the slice change under your feet, which is undefined behavior,
+/// and reading past a truncated end crashes the process with `SIGBUS`. Only
+/// map files which nobody writes to, like finished build artifacts.
+#[cfg(feature = "mmap")]
+pub unsafe fn mmap_file(path: impl AsRef<Path>) -> Result<Mmap> {
+    _mmap_file(path.as_ref())
+}
+#[cfg(feature = "mmap")]
+unsafe fn _mmap_file(path: &Path) -> Result<Mmap> {
+    let _guard = gsl::read();
+    let file = with_path(path, std::fs::File::open(path))?;
+    let inner = with_path(path, memmap2::Mmap::map(&file))?;
+    Ok(Mmap { inner })
+}
+
+/// Reads and deserializes the TOML file at `path`.
+///
+/// Requires the `toml` feature.
+#[cfg(feature = "toml")]
+pub fn read_toml<T: serde::de::DeserializeOwned>(path: impl AsRef<Path>) -> Result<T> {
+    let path = path.as_ref();
+    let text = read_file(path)?;
+    toml_crate::from_str(&text).map_err(|err| invalid_data(path, err))
+}
+
+/// Serializes `value` as TOML and writes it to `path`.
+///
+/// Requires the `toml` feature.
+#[cfg(feature = "toml")]
+pub fn write_toml<T: serde::Serialize + ?Sized>(path: impl AsRef<Path>, value: &T) -> Result<()> {
+    let path = path.as_ref();
+    let text = toml_crate::to_string_pretty(value).map_err(|err| invalid_data(path, err))?;
+    write_file(path, text)
+}
+
+/// Reads and deserializes the JSON file at `path`.
+///
+/// Requires the `json` feature.
+#[cfg(feature = "json")]
+pub fn read_json<T: serde::de::DeserializeOwned>(path: impl AsRef<Path>) -> Result<T> {
+    let path = path.as_ref();
+    let text = read_file(path)?;
+    serde_json::from_str(&text).map_err(|err| invalid_data(path, err))
+}
+
+/// Serializes `value` as pretty-printed JSON and writes it to `path`.
+///
+/// Requires the `json` feature.
+#[cfg(feature = "json")]
+pub fn write_json<T: serde::Serialize + ?Sized>(path: impl AsRef<Path>, value: &T) -> Result<()> {
+    let path = path.as_ref();
+    let mut text = serde_json::to_string_pretty(value).map_err(|err| invalid_data(path, err))?;
+    text.push('\n');
+    write_file(path, text)
+}
+
+#[cfg(any(feature = "toml", feature = "json"))]
+fn invalid_data(path: &Path, err: impl std::fmt::Display) -> crate::Error {
+    let io_err = std::io::Error::new(std::io::ErrorKind::InvalidData, err.to_string());
+    fs_err(path.to_path_buf(), io_err)
+}
+
 pub fn mkdir_p(path: impl AsRef<Path>) -> Result<()> {
     _mkdir_p(path.as_ref())
 }
@@ -37,6 +170,23 @@ fn _mkdir_p(path: &Path) -> Result<()> {
     with_path(path, std::fs::create_dir_all(path))
 }
 
+/// Creates an empty file at `path`, or updates its modification time if it
+/// exists, like `touch`.
+///
+/// Missing parent directories are created. The contents of an existing file
+/// are left intact, which makes this handy for stamp files.
+pub fn touch(path: impl AsRef<Path>) -> Result<()> {
+    _touch(path.as_ref())
+}
+fn _touch(path: &Path) -> Result<()> {
+    let _guard = gsl::read();
+    if let Some(parent) = path.parent().filter(|it| !it.as_os_str().is_empty()) {
+        with_path(parent, std::fs::create_dir_all(parent))?;
+    }
+    let file = with_path(path, OpenOptions::new().create(true).append(true).open(path))?;
+    with_path(path, set_mtime_now(&file))
+}
+
 pub fn cp(src: impl AsRef<Path>, dst: impl AsRef<Path>) -> Result<()> {
     _cp(src.as_ref(), dst.as_ref())
 }
@@ -45,21 +195,664 @@ fn _cp(src: &Path, dst: &Path) -> Result<()> {
     with_path(src, std::fs::copy(src, dst)).map(|_size| ())
 }
 
+/// Moves `src` to `dst`, like `mv`.
+///
+/// If `src` and `dst` are on different filesystems, `src` is copied and then
+/// removed instead.
+pub fn mv(src: impl AsRef<Path>, dst: impl AsRef<Path>) -> Result<()> {
+    _mv(src.as_ref(), dst.as_ref())
+}
+fn _mv(src: &Path, dst: &Path) -> Result<()> {
+    let _guard = gsl::read();
+    let res = match std::fs::rename(src, dst) {
+        Err(io_err) if is_cross_device(&io_err) && src.is_dir() => {
+            copy_tree(src, dst)?;
+            return with_path(src, remove_dir_all(src));
+        }
+        Err(io_err) if is_cross_device(&io_err) => {
+            std::fs::copy(src, dst).and_then(|_size| std::fs::remove_file(src))
+        }
+        res => res,
+    };
+    res.map_err(|io_err| fs_err2(src.to_path_buf(), dst.to_path_buf(), io_err))
+}
+
+/// Recursively copies the directory `src`, like `cp -r`.
+///
+/// If `dst` is an existing directory, the copy is created inside of it, as
+/// `dst/<name of src>`. Otherwise, the copy is created as `dst` itself.
+/// Symlinks are copied as symlinks, and are not followed, so a link to a
+/// parent directory doesn't cause an infinite loop. On Windows, where
+/// creating symlinks requires special privileges, copying a tree with a
+/// symlink in it is an error, and the files copied before it are kept.
+pub fn cp_r(src: impl AsRef<Path>, dst: impl AsRef<Path>) -> Result<()> {
+    _cp_r(src.as_ref(), dst.as_ref())
+}
+fn _cp_r(src: &Path, dst: &Path) -> Result<()> {
+    let _guard = gsl::read();
+    let dst = match src.file_name() {
+        Some(name) if dst.is_dir() => dst.join(name),
+        _ => dst.to_path_buf(),
+    };
+    copy_tree(src, &dst)
+}
+fn copy_tree(src: &Path, dst: &Path) -> Result<()> {
+    let src_abs = with_path(src, std::fs::canonicalize(src))?;
+    // `dst` doesn't exist yet, so resolve its parent instead.
+    let parent = match dst.parent() {
+        Some(it) if !it.as_os_str().is_empty() => it,
+        _ => Path::new("."),
+    };
+    if let Ok(parent) = std::fs::canonicalize(parent) {
+        if parent.join(dst.file_name().unwrap_or_default()).starts_with(&src_abs) {
+            let io_err =
+                std::io::Error::new(std::io::ErrorKind::InvalidInput, "can't copy into itself");
+            return Err(fs_err2(src.to_path_buf(), dst.to_path_buf(), io_err));
+        }
+    }
+    copy_tree_aux(src, dst)
+}
+fn copy_tree_aux(src: &Path, dst: &Path) -> Result<()> {
+    with_path(dst, std::fs::create_dir_all(dst))?;
+    for entry in with_path(src, std::fs::read_dir(src))? {
+        let entry = with_path(src, entry)?;
+        let src = entry.path();
+        let dst = dst.join(entry.file_name());
+        let file_type = with_path(&src, entry.file_type())?;
+        if file_type.is_dir() {
+            copy_tree_aux(&src, &dst)?;
+        } else if file_type.is_symlink() {
+            #[cfg(unix)]
+            let res = std::fs::read_link(&src)
+                .and_then(|target| std::os::unix::fs::symlink(target, &dst));
+            #[cfg(not(unix))]
+            let res = Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "can't copy a symlink on this platform",
+            ));
+            res.map_err(|io_err| fs_err2(src.clone(), dst.clone(), io_err))?;
+        } else {
+            std::fs::copy(&src, &dst)
+                .map_err(|io_err| fs_err2(src.clone(), dst.clone(), io_err))?;
+        }
+    }
+    Ok(())
+}
+
+/// Copies `src` to `dst`, unless `dst` exists and is at least as new as
+/// `src`. Returns whether the file was copied.
+///
+/// Timestamps come from the file system, whose resolution varies: on some
+/// file systems, a `src` modified right after `dst` has the same mtime and
+/// isn't copied.
+///
+/// Requires the `extra-fs` feature.
+#[cfg(feature = "extra-fs")]
+pub fn cp_if_newer(src: impl AsRef<Path>, dst: impl AsRef<Path>) -> Result<bool> {
+    _cp_if_newer(src.as_ref(), dst.as_ref())
+}
+#[cfg(feature = "extra-fs")]
+fn _cp_if_newer(src: &Path, dst: &Path) -> Result<bool> {
+    let _guard = gsl::read();
+    let src_mtime = with_path(src, std::fs::metadata(src).and_then(|it| it.modified()))?;
+    let dst_mtime = match std::fs::metadata(dst) {
+        Ok(metadata) => Some(with_path(dst, metadata.modified())?),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+        Err(err) => return Err(fs_err(dst.to_path_buf(), err)),
+    };
+    if dst_mtime.map_or(false, |dst_mtime| dst_mtime >= src_mtime) {
+        return Ok(false);
+    }
+    with_path(src, std::fs::copy(src, dst))?;
+    Ok(true)
+}
+
+/// Copies `src` to `dst`, unless `dst` already has the same contents.
+/// Returns whether the file was copied.
+///
+/// Unlike [`cp_if_newer`], this doesn't depend on timestamps, and leaves the
+/// mtime of an unchanged `dst` alone, so that build tools don't consider it
+/// modified.
+///
+/// Requires the `extra-fs` feature.
+#[cfg(feature = "extra-fs")]
+pub fn cp_if_changed(src: impl AsRef<Path>, dst: impl AsRef<Path>) -> Result<bool> {
+    _cp_if_changed(src.as_ref(), dst.as_ref())
+}
+#[cfg(feature = "extra-fs")]
+fn _cp_if_changed(src: &Path, dst: &Path) -> Result<bool> {
+    let _guard = gsl::read();
+    let src_len = with_path(src, std::fs::metadata(src))?.len();
+    let dst_len = match std::fs::metadata(dst) {
+        Ok(metadata) => Some(metadata.len()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+        Err(err) => return Err(fs_err(dst.to_path_buf(), err)),
+    };
+    if dst_len == Some(src_len)
+        && with_path(src, std::fs::read(src))? == with_path(dst, std::fs::read(dst))?
+    {
+        return Ok(false);
+    }
+    with_path(src, std::fs::copy(src, dst))?;
+    Ok(true)
+}
+
+/// Creates a hard link `dst` pointing to the same file as `src`.
+///
+/// Hard links can't span filesystems, the error says so explicitly.
+///
+/// Requires the `extra-fs` feature.
+#[cfg(feature = "extra-fs")]
+pub fn hard_link(src: impl AsRef<Path>, dst: impl AsRef<Path>) -> Result<()> {
+    _hard_link(src.as_ref(), dst.as_ref())
+}
+#[cfg(feature = "extra-fs")]
+fn _hard_link(src: &Path, dst: &Path) -> Result<()> {
+    let _guard = gsl::read();
+    std::fs::hard_link(src, dst).map_err(|io_err| {
+        let io_err = if is_cross_device(&io_err) {
+            let msg = format!("hard links can't span filesystems: {}", io_err);
+            std::io::Error::new(io_err.kind(), msg)
+        } else {
+            io_err
+        };
+        fs_err2(src.to_path_buf(), dst.to_path_buf(), io_err)
+    })
+}
+
+/// Creates a symbolic link `link` pointing to `original`.
+///
+/// A relative `original` is resolved relative to the directory of `link`,
+/// not to the current directory. On Windows, the link is a directory or a
+/// file symlink depending on what `original` is, and creating symlinks
+/// requires either administrator privileges or Developer Mode.
+///
+/// Requires the `extra-fs` feature.
+#[cfg(feature = "extra-fs")]
+pub fn symlink(original: impl AsRef<Path>, link: impl AsRef<Path>) -> Result<()> {
+    _symlink(original.as_ref(), link.as_ref())
+}
+#[cfg(feature = "extra-fs")]
+fn _symlink(original: &Path, link: &Path) -> Result<()> {
+    let _guard = gsl::read();
+    #[cfg(unix)]
+    let res = std::os::unix::fs::symlink(original, link);
+    #[cfg(windows)]
+    let res = {
+        let target = link.parent().unwrap_or_else(|| Path::new("")).join(original);
+        if target.is_dir() {
+            std::os::windows::fs::symlink_dir(original, link)
+        } else {
+            std::os::windows::fs::symlink_file(original, link)
+        }
+    };
+    res.map_err(|io_err| fs_err2(original.to_path_buf(), link.to_path_buf(), io_err))
+}
+
+fn is_cross_device(io_err: &std::io::Error) -> bool {
+    // `EXDEV` on Unix, `ERROR_NOT_SAME_DEVICE` on Windows.
+    let code = if cfg!(windows) { 17 } else { 18 };
+    io_err.raw_os_error() == Some(code)
+}
+
+/// Creates a new, empty file with a unique name in the system temporary
+/// directory.
+///
+/// The file is deleted when the returned [`TempFile`] is dropped.
+pub fn mktemp_f() -> Result<TempFile> {
+    let _guard = gsl::read();
+    loop {
+        let path = temp_path();
+        match std::fs::OpenOptions::new().read(true).write(true).create_new(true).open(&path) {
+            Ok(file) => return Ok(TempFile { path, file }),
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(fs_err(path, err)),
+        }
+    }
+}
+
+/// Returns a fresh path in the system temporary directory, unique among the
+/// running processes.
+fn temp_path() -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+    std::env::temp_dir().join(format!("xshell-tmp-{}-{}", std::process::id(), n))
+}
+
+/// Creates a uniquely named directory in the system temporary directory,
+/// passes its path to `f`, and removes the directory afterwards.
+///
+/// The directory is removed even if `f` fails or panics.
+///
+/// ```
+/// use xshell::{read_file, with_temp_dir, write_file};
+///
+/// let text = with_temp_dir(|dir| {
+///     write_file(dir.join("hello.txt"), "hello")?;
+///     read_file(dir.join("hello.txt"))
+/// })?;
+/// assert_eq!(text, "hello");
+/// # Ok::<(), xshell::Error>(())
+/// ```
+pub fn with_temp_dir<T>(f: impl FnOnce(&Path) -> Result<T>) -> Result<T> {
+    let dir = temp_dir()?;
+    f(dir.path())
+}
+
+/// Creates a uniquely named directory in the system temporary directory.
+///
+/// The directory and its contents are removed when the returned [`TempDir`]
+/// is dropped.
+pub fn temp_dir() -> Result<TempDir> {
+    let _guard = gsl::read();
+    loop {
+        let path = temp_path();
+        match std::fs::create_dir(&path) {
+            Ok(()) => return Ok(TempDir { path }),
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(fs_err(path, err)),
+        }
+    }
+}
+
+/// A temporary directory created by [`temp_dir`], removed on drop.
+#[must_use]
+#[derive(Debug)]
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    /// Returns the path of the directory, which is kept instead of being
+    /// removed.
+    pub fn into_path(self) -> PathBuf {
+        let mut this = std::mem::ManuallyDrop::new(self);
+        std::mem::take(&mut this.path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = rm_rf(&self.path);
+    }
+}
+
+/// A temporary file created by [`mktemp_f`], deleted on drop.
+#[must_use]
+#[derive(Debug)]
+pub struct TempFile {
+    path: PathBuf,
+    file: File,
+}
+
+impl TempFile {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Returns the paths of the entries of the directory `path`, sorted.
 pub fn read_dir(path: impl AsRef<Path>) -> Result<Vec<PathBuf>> {
     _read_dir(path.as_ref())
 }
 fn _read_dir(path: &Path) -> Result<Vec<PathBuf>> {
     let _guard = gsl::read();
+    check_not_file(path)?;
     with_path(path, read_dir_aux(path))
 }
 
+/// Like [`read_dir`], but also returns the type of each entry, which usually
+/// comes for free with the listing, without a separate `stat`.
+///
+/// ```
+/// # use xshell::read_dir_entries;
+/// let dirs = read_dir_entries(".")?.into_iter().filter(|it| it.is_dir());
+/// # Ok::<(), xshell::Error>(())
+/// ```
+///
+/// Requires the `extra-fs` feature.
+#[cfg(feature = "extra-fs")]
+pub fn read_dir_entries(path: impl AsRef<Path>) -> Result<Vec<DirEntry>> {
+    _read_dir_entries(path.as_ref())
+}
+#[cfg(feature = "extra-fs")]
+fn _read_dir_entries(path: &Path) -> Result<Vec<DirEntry>> {
+    let _guard = gsl::read();
+    check_not_file(path)?;
+    let mut res = Vec::new();
+    for entry in with_path(path, std::fs::read_dir(path))? {
+        let entry = with_path(path, entry)?;
+        let file_type = with_path(&entry.path(), entry.file_type())?;
+        res.push(DirEntry { path: entry.path(), file_type });
+    }
+    res.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(res)
+}
+
+/// An entry of a directory, returned by [`read_dir_entries`].
+///
+/// Requires the `extra-fs` feature.
+#[cfg(feature = "extra-fs")]
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    path: PathBuf,
+    file_type: std::fs::FileType,
+}
+
+#[cfg(feature = "extra-fs")]
+impl DirEntry {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    pub fn file_name(&self) -> &std::ffi::OsStr {
+        self.path.file_name().unwrap()
+    }
+    pub fn is_dir(&self) -> bool {
+        self.file_type.is_dir()
+    }
+    pub fn is_file(&self) -> bool {
+        self.file_type.is_file()
+    }
+    /// Whether the entry itself is a symlink, which is not followed.
+    pub fn is_symlink(&self) -> bool {
+        self.file_type.is_symlink()
+    }
+}
+
+/// Recursively lists everything below `root`, files and directories alike.
+///
+/// Directories come before their contents, entries of a directory are
+/// sorted. Symlinks are listed but not followed, so there are no cycles. See
+/// [`walk_dir_iter`] for a lazy version.
+///
+/// Requires the `extra-fs` feature.
+#[cfg(feature = "extra-fs")]
+pub fn walk_dir(root: impl AsRef<Path>) -> Result<Vec<PathBuf>> {
+    walk_dir_iter(root).collect()
+}
+
+/// Like [`walk_dir`], but reads directories as the iteration goes.
+///
+/// ```
+/// # use xshell::walk_dir_iter;
+/// for path in walk_dir_iter("src") {
+///     let path = path?;
+///     assert!(path.starts_with("src"));
+/// }
+/// # Ok::<(), xshell::Error>(())
+/// ```
+///
+/// Requires the `extra-fs` feature.
+#[cfg(feature = "extra-fs")]
+pub fn walk_dir_iter(root: impl AsRef<Path>) -> WalkDir {
+    WalkDir { root: Some(root.as_ref().to_path_buf()), pending: Vec::new() }
+}
+
+/// Iterator returned by [`walk_dir_iter`].
+///
+/// Requires the `extra-fs` feature.
+#[cfg(feature = "extra-fs")]
+#[derive(Debug)]
+pub struct WalkDir {
+    root: Option<PathBuf>,
+    /// Paths yet to be yielded, the next one last.
+    pending: Vec<PathBuf>,
+}
+
+#[cfg(feature = "extra-fs")]
+impl WalkDir {
+    fn push_children(&mut self, dir: &Path) -> Result<()> {
+        let _guard = gsl::read();
+        let children = with_path(dir, read_dir_aux(dir))?;
+        self.pending.extend(children.into_iter().rev());
+        Ok(())
+    }
+}
+
+#[cfg(feature = "extra-fs")]
+impl Iterator for WalkDir {
+    type Item = Result<PathBuf>;
+
+    fn next(&mut self) -> Option<Result<PathBuf>> {
+        if let Some(root) = self.root.take() {
+            if let Err(err) = self.push_children(&root) {
+                return Some(Err(err));
+            }
+        }
+        let path = self.pending.pop()?;
+        let file_type = match with_path(&path, std::fs::symlink_metadata(&path)) {
+            Ok(it) => it.file_type(),
+            Err(err) => return Some(Err(err)),
+        };
+        if file_type.is_dir() {
+            if let Err(err) = self.push_children(&path) {
+                return Some(Err(err));
+            }
+        }
+        Some(Ok(path))
+    }
+}
+
+/// Expands `pattern` into the sorted list of existing paths it matches.
+///
+/// In the pattern, `*` matches any sequence of characters within a path
+/// component, `?` matches any single character, and a `**` component matches
+/// any number of nested directories, including none. Relative patterns give
+/// relative paths, absolute ones give absolute paths. Matching nothing is not
+/// an error.
+///
+/// Like in a shell, hidden files and directories, whose names start with
+/// `.`, are matched only by a pattern component which starts with `.` too,
+/// and `**` doesn't descend into them. Directories which can't be read are
+/// skipped.
+///
+/// ```
+/// # use xshell::glob;
+/// let sources = glob("src/**/*.rs")?;
+/// assert!(sources.iter().any(|it| it.ends_with("lib.rs")));
+/// # Ok::<(), xshell::Error>(())
+/// ```
+///
+/// Requires the `extra-fs` feature.
+#[cfg(feature = "extra-fs")]
+pub fn glob(pattern: &str) -> Result<Vec<PathBuf>> {
+    let _guard = gsl::read();
+    let mut candidates = vec![PathBuf::new()];
+    for component in Path::new(pattern).components() {
+        let component = component.as_os_str();
+        let wildcard = match component.to_str() {
+            Some("**") => {
+                let mut next = Vec::new();
+                for dir in candidates {
+                    let read_path = if dir.as_os_str().is_empty() { Path::new(".") } else { &dir };
+                    // Not `walk_dir`, which would take the lock again.
+                    let mut paths = Vec::new();
+                    glob_walk(read_path, &mut paths);
+                    for path in paths {
+                        next.push(dir.join(path.strip_prefix(read_path).unwrap()));
+                    }
+                    next.push(dir);
+                }
+                candidates = next;
+                continue;
+            }
+            Some(it) if it.contains(['*', '?']) => it,
+            _ => {
+                candidates.iter_mut().for_each(|it| it.push(component));
+                continue;
+            }
+        };
+        let mut next = Vec::new();
+        for dir in &candidates {
+            let read_path = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+            let entries = match std::fs::read_dir(read_path) {
+                Ok(it) => it,
+                Err(_) => continue,
+            };
+            for entry in entries {
+                let name = with_path(read_path, entry)?.file_name();
+                let matches = name.to_str().map_or(false, |name| {
+                    (!name.starts_with('.') || wildcard.starts_with('.'))
+                        && wildcard_match(wildcard, name)
+                });
+                if matches {
+                    next.push(dir.join(name));
+                }
+            }
+        }
+        candidates = next;
+    }
+    candidates.retain(|it| !it.as_os_str().is_empty() && it.exists());
+    candidates.sort();
+    candidates.dedup();
+    Ok(candidates)
+}
+
+/// Collects everything below `dir` for a `**` in [`glob`], skipping hidden
+/// entries and unreadable directories.
+#[cfg(feature = "extra-fs")]
+fn glob_walk(dir: &Path, res: &mut Vec<PathBuf>) {
+    let children = match read_dir_aux(dir) {
+        Ok(it) => it,
+        Err(_) => return,
+    };
+    for path in children {
+        if path.file_name().map_or(false, |it| it.to_string_lossy().starts_with('.')) {
+            continue;
+        }
+        let is_dir = std::fs::symlink_metadata(&path).map_or(false, |it| it.is_dir());
+        res.push(path.clone());
+        if is_dir {
+            glob_walk(&path, res);
+        }
+    }
+}
+
+#[cfg(feature = "extra-fs")]
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let text = text.chars().collect::<Vec<_>>();
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` in the pattern and of the text it was matched at.
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    backtrack = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Lexically resolves `.` and `..` components and redundant separators in
+/// `path`.
+///
+/// Unlike [`std::fs::canonicalize`], this doesn't touch the file system: the
+/// path doesn't have to exist, symlinks are not followed and relative paths
+/// stay relative. Because of symlinks, `a/../b` might actually refer to a
+/// different file than `b`.
+pub fn normalize(path: impl AsRef<Path>) -> PathBuf {
+    _normalize(path.as_ref())
+}
+fn _normalize(path: &Path) -> PathBuf {
+    let mut res = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => match res.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    res.pop();
+                }
+                // `..` of the root is the root itself.
+                Some(Component::RootDir) | Some(Component::Prefix(_)) => (),
+                Some(Component::ParentDir) | Some(Component::CurDir) | None => res.push(".."),
+            },
+            _ => res.push(component),
+        }
+    }
+    if res.as_os_str().is_empty() {
+        res.push(".");
+    }
+    res
+}
+
+/// Returns the path of the binary `name` built by cargo, like
+/// `target/debug/name`, or `target/release/name.exe` on Windows.
+///
+/// Honors `CARGO_TARGET_DIR`. Errors if the binary doesn't exist.
+pub fn target_bin(name: &str, release: bool) -> Result<PathBuf> {
+    let _guard = gsl::read();
+    let target_dir = match std::env::var_os("CARGO_TARGET_DIR") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from("target"),
+    };
+    let profile = if release { "release" } else { "debug" };
+    let file_name = format!("{}{}", name, std::env::consts::EXE_SUFFIX);
+    let path = target_dir.join(profile).join(file_name);
+    if !path.is_file() {
+        let io_err = std::io::Error::new(std::io::ErrorKind::NotFound, "binary not found");
+        return Err(fs_err(path, io_err));
+    }
+    Ok(path)
+}
+
 pub fn cwd() -> Result<PathBuf> {
     let _guard = gsl::read();
-    with_path(&Path::new("."), std::env::current_dir())
+    with_path(Path::new("."), std::env::current_dir())
 }
 
 fn with_path<T>(path: &Path, res: Result<T, std::io::Error>) -> Result<T> {
-    res.map_err(|io_err| fs_err(path.to_path_buf(), io_err))
+    res.map_err(|io_err| path_err(path, io_err))
+}
+fn path_err(path: &Path, io_err: std::io::Error) -> Error {
+    let io_err = if is_path_too_long(path, &io_err) {
+        let msg = format!(
+            "path is longer than MAX_PATH, use a shorter name or an extended-length \
+             `\\\\?\\` path: {}",
+            io_err
+        );
+        std::io::Error::new(io_err.kind(), msg)
+    } else {
+        io_err
+    };
+    fs_err(path.to_path_buf(), io_err)
+}
+
+fn is_path_too_long(path: &Path, io_err: &std::io::Error) -> bool {
+    // `ERROR_FILENAME_EXCED_RANGE`, or `ERROR_PATH_NOT_FOUND` for a path which
+    // doesn't fit into `MAX_PATH`.
+    cfg!(windows)
+        && match io_err.raw_os_error() {
+            Some(206) => true,
+            Some(3) => path.as_os_str().len() >= 260,
+            _ => false,
+        }
 }
 
 #[cfg(not(windows))]
@@ -78,12 +871,63 @@ fn remove_dir_all(path: &Path) -> std::io::Result<()> {
     std::fs::remove_dir_all(path)
 }
 
+/// Errors for a file, so that listing one says "not a directory".
+fn check_not_file(path: &Path) -> Result<()> {
+    if path.is_file() {
+        let io_err = std::io::Error::new(std::io::ErrorKind::Other, "not a directory");
+        return Err(fs_err(path.to_path_buf(), io_err));
+    }
+    Ok(())
+}
+
 fn read_dir_aux(path: &Path) -> std::io::Result<Vec<PathBuf>> {
     let mut res = Vec::new();
     for entry in std::fs::read_dir(path)? {
         let entry = entry?;
         res.push(entry.path())
     }
-    res.sort();
-    Ok(res)
+    // A heap sort instantiates a fraction of the code of `sort`.
+    Ok(std::collections::BinaryHeap::from(res).into_sorted_vec())
+}
+
+#[cfg(unix)]
+fn set_mtime_now(file: &File) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    extern "C" {
+        fn futimens(fd: i32, times: *const std::ffi::c_void) -> i32;
+    }
+    // Null `times` sets both the access and modification times to now.
+    if unsafe { futimens(file.as_raw_fd(), std::ptr::null()) } == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+#[cfg(windows)]
+fn set_mtime_now(file: &File) -> std::io::Result<()> {
+    use std::os::windows::io::AsRawHandle;
+    #[repr(C)]
+    struct FileTime {
+        low: u32,
+        high: u32,
+    }
+    extern "system" {
+        fn GetSystemTimeAsFileTime(time: *mut FileTime);
+        fn SetFileTime(
+            handle: std::os::windows::io::RawHandle,
+            created: *const FileTime,
+            accessed: *const FileTime,
+            written: *const FileTime,
+        ) -> i32;
+    }
+    let mut now = FileTime { low: 0, high: 0 };
+    unsafe {
+        GetSystemTimeAsFileTime(&mut now);
+        if SetFileTime(file.as_raw_handle(), std::ptr::null(), &now, &now) != 0 {
+            Ok(())
+        } else {
+            Err(std::io::Error::last_os_error())
+        }
+    }
 }
@@ -1,9 +1,6 @@
 //! Global shell lock
 use std::{
     cell::Cell,
-    mem::MaybeUninit,
-    ptr,
-    sync::Once,
     sync::{RwLock, RwLockReadGuard, RwLockWriteGuard},
 };
 
@@ -32,16 +29,11 @@ pub(crate) fn read() -> Guard {
 }
 
 fn static_rw_lock() -> &'static RwLock<()> {
-    static mut LOCK: MaybeUninit<RwLock<()>> = MaybeUninit::uninit();
-    static LOCK_INIT: Once = Once::new();
-    unsafe {
-        LOCK_INIT.call_once(|| ptr::write(LOCK.as_mut_ptr(), RwLock::new(())));
-        &*LOCK.as_ptr()
-    }
+    global!(RwLock<()>, RwLock::new(()))
 }
 
 thread_local! {
-    static LOCKED: Cell<bool> = Cell::new(false);
+    static LOCKED: Cell<bool> = const { Cell::new(false) };
 }
 
 impl Drop for Guard {
@@ -0,0 +1,97 @@
+//! Killing children on Ctrl-C
+use std::{
+    io,
+    process::Child,
+    sync::atomic::{AtomicBool, Ordering},
+    sync::{Mutex, Once, RwLock},
+};
+
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+/// Installs a Ctrl-C handler which kills all running children spawned by
+/// xshell, and then exits the process with code 130.
+///
+/// Requires the `ctrlc` feature. The handler is process-global: it replaces
+/// the default behavior of `SIGINT` for the whole program, and conflicts with
+/// any other Ctrl-C handler. Calling this function more than once is fine.
+///
+/// On Windows, Ctrl-C is delivered to every process attached to the console,
+/// so the handler only exits.
+pub fn install_ctrlc_handler() -> io::Result<()> {
+    static INSTALL: Once = Once::new();
+    let mut res = Ok(());
+    INSTALL.call_once(|| {
+        res = ctrlc_crate::set_handler(|| {
+            kill_children();
+            std::process::exit(130)
+        })
+        .map_err(|err| io::Error::new(io::ErrorKind::Other, err));
+        if res.is_ok() {
+            INSTALLED.store(true, Ordering::SeqCst);
+        }
+    });
+    res
+}
+
+/// Spawns a child and remembers it, if the handler is installed.
+///
+/// Concurrent spawns don't block each other, but the handler waits for them
+/// to register their children, so that Ctrl-C can't slip in between
+/// spawning and registration. Children which have been waited for are
+/// forgotten at the next spawn.
+pub(crate) fn spawn(spawn: impl FnOnce() -> io::Result<Child>) -> io::Result<Child> {
+    if !INSTALLED.load(Ordering::SeqCst) {
+        return spawn();
+    }
+    let _spawning = spawning().read().unwrap_or_else(|err| err.into_inner());
+    let child = spawn()?;
+    let mut children = children().lock().unwrap_or_else(|err| err.into_inner());
+    children.retain(|&pid| !is_reaped(pid));
+    children.push(child.id());
+    Ok(child)
+}
+
+/// Whether `pid` is no longer a child of this process, that is, whether it
+/// has been waited for. Unlike `waitpid`, this doesn't reap the child.
+#[cfg(unix)]
+fn is_reaped(pid: u32) -> bool {
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+    let options = libc::WEXITED | libc::WNOHANG | libc::WNOWAIT;
+    let res = unsafe { libc::waitid(libc::P_PID, pid as libc::id_t, &mut info, options) };
+    res == -1 && io::Error::last_os_error().raw_os_error() == Some(libc::ECHILD)
+}
+
+#[cfg(not(unix))]
+fn is_reaped(_pid: u32) -> bool {
+    false
+}
+
+#[cfg(unix)]
+fn kill_children() {
+    let _spawning = spawning().write().unwrap_or_else(|err| err.into_inner());
+    let children = children().lock().unwrap_or_else(|err| err.into_inner());
+    for &pid in children.iter() {
+        let pid = pid as libc::pid_t;
+        // Only kill pids which are still our running children: an already
+        // reaped pid might have been reused by an unrelated process. Reaping
+        // zombies here is harmless, as the process is about to exit.
+        let mut status = 0;
+        if unsafe { libc::waitpid(pid, &mut status, libc::WNOHANG) } == 0 {
+            unsafe {
+                libc::kill(pid, libc::SIGKILL);
+                libc::waitpid(pid, &mut status, 0);
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn kill_children() {}
+
+fn children() -> &'static Mutex<Vec<u32>> {
+    global!(Mutex<Vec<u32>>, Mutex::new(Vec::new()))
+}
+
+fn spawning() -> &'static RwLock<()> {
+    global!(RwLock<()>, RwLock::new(()))
+}
@@ -6,6 +6,7 @@
 //! ```
 //! use xshell::{cmd, read_file};
 //!
+//! # #[cfg(feature = "macros")] {
 //! let name = "Julia";
 //! let output = cmd!("echo hello {name}!").read()?;
 //! assert_eq!(output, "hello Julia!");
@@ -15,6 +16,7 @@
 //!     err.to_string(),
 //!     "`feeling-lucky.txt`: no such file or directory (os error 2)",
 //! );
+//! # }
 //! # Ok::<(), xshell::Error>(())
 //! ```
 //!
@@ -81,26 +83,99 @@
 //!
 //! Interpolation is supported via `{name}` syntax. Use `{name...}` to
 //! interpolate sequence of values.
+//! Values which implement `AsRef<OsStr>`, like `&str` or `PathBuf`, are
+//! passed to the command as is, without conversion to UTF-8 and without
+//! splitting on whitespace. Other values are formatted with `Display`.
+//!
+//! Any expression can be interpolated, not just variables:
+//!
+//! ```
+//! # use xshell::cmd;
+//! # #[cfg(feature = "macros")] {
+//! let jobs = 4;
+//! let src = std::path::Path::new("src");
+//! assert_eq!(
+//!     cmd!("make -j{jobs * 2} -C {src.join(\"sub\")}").to_string(),
+//!     "make -j8 -C src/sub"
+//! );
+//! # }
+//! ```
 //!
 //! ```
 //! # use xshell::cmd;
+//! # #[cfg(feature = "macros")] {
 //! let greeting = "Guten Tag";
 //! let people = &["Spica", "Boarst", "Georgina"];
 //! assert_eq!(
 //!     cmd!("echo {greeting} {people...}").to_string(),
 //!     r#"echo "Guten Tag" Spica Boarst Georgina"#
 //! );
+//! # }
 //! ```
 //!
 //! Splat syntax is used for optional argument idiom.
 //!
 //! ```
 //! # use xshell::cmd;
+//! # #[cfg(feature = "macros")] {
 //! let dry_run = if true { &["--dry-run"] } else { &[][..] };
 //! assert_eq!(
 //!     cmd!("git push {dry_run...}").to_string(),
 //!     "git push --dry-run"
 //! );
+//! # }
+//! ```
+//!
+//! For a single boolean switch, `{name:--flag}` expands to `--flag` if `name`
+//! is `true`, and to nothing otherwise. The flag must start with `-` and can't
+//! contain whitespace.
+//!
+//! ```
+//! # use xshell::cmd;
+//! # #[cfg(feature = "macros")] {
+//! let release = true;
+//! assert_eq!(
+//!     cmd!("cargo build {release:--release}").to_string(),
+//!     "cargo build --release"
+//! );
+//!
+//! let release = false;
+//! assert_eq!(
+//!     cmd!("cargo build {release:--release}").to_string(),
+//!     "cargo build"
+//! );
+//! # }
+//! ```
+//!
+//! `{value:spec}` formats the value with a `format!` spec, which is handy in
+//! the middle of an argument:
+//!
+//! ```
+//! # use xshell::cmd;
+//! # #[cfg(feature = "macros")] {
+//! let id = 7;
+//! assert_eq!(cmd!("tool --id={id:04} --mask={id:#x}").to_string(), "tool --id=0007 --mask=0x7");
+//! # }
+//! ```
+//!
+//! A spec can't be combined with splat:
+//!
+//! ```compile_fail
+//! # use xshell::cmd;
+//! let ids = [1, 2];
+//! cmd!("tool {ids:04...}");
+//! ```
+//!
+//! An unquoted `|` connects the stdout of a command to the stdin of the next
+//! one, like [`Cmd::pipe`].
+//!
+//! ```
+//! # use xshell::cmd;
+//! # #[cfg(feature = "macros")] {
+//! let n = cmd!("printf 'a\\nb\\n' | wc -l").read()?;
+//! assert_eq!(n.trim(), "2");
+//! # }
+//! # Ok::<(), xshell::Error>(())
 //! ```
 //!
 //! ## Manipulating the Environment
@@ -137,9 +212,14 @@
 //!
 //! # Maintenance
 //!
-//! Minimum Supported Rust Version: 1.47.0. MSRV bump is not considered semver
+//! Minimum Supported Rust Version: 1.59.0. MSRV bump is not considered semver
 //! breaking. MSRV is updated conservatively.
 //!
+//! The MSRV covers the default features only. The optional features pull in
+//! third-party crates (`libc`, `ctrlc`, `memmap2`, `flate2`, `serde_json`,
+//! `toml`), whose recent versions may need a newer compiler; CI doesn't check
+//! them against 1.59.
+//!
 //! The crate isn't comprehensive. Additional functionality is added on
 //! as-needed bases, as long as it doesn't compromise compile times.
 //! Function-level docs are an especially welcome addition :-)
@@ -167,6 +247,24 @@
 //! To make IDEs infer correct types without expanding proc-macro, it is wrapped
 //! into a declarative macro which supplies type hints.
 //!
+//! The proc-macro is behind the default `macros` feature. Without it, `cmd!`
+//! is a declarative macro which only accepts plain command lines, like
+//! `cmd!("cargo build --release")`, and doesn't support interpolation or
+//! pipelines: `{name}`, `{name...}` and `|` are a compile error. Interpolate
+//! with [`Cmd::arg`] and [`Cmd::args`], and connect commands with
+//! [`Cmd::pipe`], instead. The literal is split at runtime, exactly like the
+//! proc-macro would split it.
+//!
+//! Either way, arguments are separated by whitespace and can be grouped
+//! with single quotes. There is no other quoting or escaping, double quotes
+//! and backslashes are ordinary characters:
+//!
+//! ```
+//! # use xshell::cmd;
+//! let cmd = cmd!(r#"echo 'a b' "c d" e\f '{}|'"#);
+//! assert_eq!(cmd.get_args(), ["a b", "\"c", "d\"", r"e\f", "{}|"]);
+//! ```
+//!
 //! Environment manipulation mutates global state and might have surprising
 //! interactions with threads. Internally, everything is protected by a global
 //! shell lock, so all functions in this crate are thread safe. However,
@@ -195,6 +293,14 @@
 //! # t1.join().unwrap(); t2.join().unwrap(); t3.join().unwrap();
 //! ```
 //!
+//! Spawning a command is one of those: it doesn't wait for the lock, and the
+//! child inherits the working directory and environment of the moment, which
+//! another thread's [`pushd`] or [`pushenv`] might have changed. Commands
+//! which need to be isolated from that should use [`Cmd::current_dir`] and
+//! [`Cmd::env`] instead. The lock is only taken, in shared mode, while a
+//! command reads the state of the process itself, like the variables kept by
+//! [`Cmd::hermetic_env`] or a relative [`Cmd::current_dir`].
+//!
 //! # Naming
 //!
 //! xshell is an ex-shell, for those who grew tired of bash.<br>
@@ -203,66 +309,590 @@
 //! xshell uses x-traordinary level of [trickery](https://github.com/matklad/xshell/blob/843df7cd5b7d69fc9d2b884dc0852598335718fe/src/lib.rs#L233-L234),
 //! just like `xtask` [does](https://matklad.github.io/2018/01/03/make-your-own-make.html).
 
+/// Evaluates to a `&'static $ty`, initialized with `$init` on first use.
+macro_rules! global {
+    ($ty:ty, $init:expr) => {{
+        static mut VALUE: *const $ty = std::ptr::null();
+        static INIT: std::sync::Once = std::sync::Once::new();
+        unsafe {
+            INIT.call_once(|| VALUE = Box::into_raw(Box::new($init)));
+            &*VALUE
+        }
+    }};
+}
+
+mod echo;
 mod env;
 mod gsl;
 mod error;
+pub mod exit_code;
 mod fs;
+#[cfg(feature = "ctrlc")]
+mod interrupt;
+#[cfg(feature = "mock")]
+mod mock;
 
 use std::{
+    any::Any,
     ffi::{OsStr, OsString},
-    fmt, io,
-    io::Write,
-    path::Path,
+    fmt,
+    fs::File,
+    io,
+    io::{Read, Write},
+    marker::PhantomData,
+    path::{Path, PathBuf},
     process::Output,
-    process::Stdio,
+    process::{Child, ChildStderr, ChildStdout, ExitStatus, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
-use error::CmdErrorKind;
+#[cfg(feature = "extra-cmd")]
+use std::{collections::VecDeque, sync::Condvar};
+
+#[cfg(feature = "extra-cmd")]
+use error::cmd_line_err;
+use error::{CmdErrorKind, EmptyProgram, Timeout};
+#[cfg(feature = "macros")]
 #[doc(hidden)]
 pub use xshell_macros::__cmd;
 
 pub use crate::{
-    env::{pushd, pushenv, Pushd, Pushenv},
+    echo::{default_echo_hook, set_echo_hook},
+    env::{
+        cd, env_or, is_tty_stderr, is_tty_stdout, num_cpus, pushd, pushenv, pushenv_prev,
+        pushenv_remove, Pushd, Pushenv,
+    },
     error::{Error, Result},
-    fs::{cp, cwd, mkdir_p, read_dir, read_file, rm_rf, write_file},
+    fs::{
+        cp, cp_r, cwd, mkdir_p, mktemp_f, mv, normalize, read_dir, read_file, read_file_bytes,
+        rm_rf, rm_rf_strict, target_bin, temp_dir, touch, with_temp_dir, write_file, TempDir,
+        TempFile,
+    },
+};
+
+#[cfg(feature = "extra-fs")]
+pub use crate::{
+    env::{join_path_dirs, path_dirs, pushd_glob, which},
+    fs::{
+        cp_if_changed, cp_if_newer, glob, hard_link, read_dir_entries, symlink, walk_dir,
+        walk_dir_iter, DirEntry, WalkDir,
+    },
 };
 
+#[cfg(feature = "mmap")]
+pub use crate::fs::{mmap_file, Mmap};
+#[cfg(feature = "json")]
+pub use crate::fs::{read_json, write_json};
+#[cfg(feature = "toml")]
+pub use crate::fs::{read_toml, write_toml};
+#[cfg(feature = "ctrlc")]
+pub use crate::interrupt::install_ctrlc_handler;
+#[cfg(feature = "mock")]
+pub use crate::mock::{set_mock, MockGuard, MockOutput};
+
+#[cfg(feature = "macros")]
 #[macro_export]
 macro_rules! cmd {
     ($cmd:tt) => {{
-        #[cfg(trick_rust_analyzer_into_highlighting_interpolated_bits)]
-        format_args!($cmd);
+        #[allow(unexpected_cfgs)]
+        {
+            #[cfg(trick_rust_analyzer_into_highlighting_interpolated_bits)]
+            format_args!($cmd);
+        }
         use $crate::Cmd as __CMD;
+        #[allow(unused_imports)]
+        use $crate::__private::{Arg as __ARG, DisplayArg as _, OsStrArg as _};
         let cmd: $crate::Cmd = $crate::__cmd!(__CMD $cmd);
         cmd
     }};
 }
 
+/// Without the proc-macro, the literal is split at runtime, and anything
+/// which needs to look at its contents at compile time is an error.
+#[cfg(not(feature = "macros"))]
+#[macro_export]
+macro_rules! cmd {
+    ($cmd:literal) => {{
+        const _: () = ::std::assert!(
+            !$crate::__private::needs_proc_macro($cmd),
+            "interpolation and pipelines in `cmd!` require the `macros` feature"
+        );
+        const _: () = ::std::assert!(
+            $crate::__private::is_well_formed($cmd),
+            "`cmd!` needs a program, and its single quotes must be balanced"
+        );
+        let cmd: $crate::Cmd = $crate::__private::split($cmd);
+        cmd
+    }};
+}
+
+/// Implementation details of [`cmd!`].
+///
+/// Values which don't implement `AsRef<OsStr>` are interpolated with
+/// autoref-based specialization: `(&Arg(x)).__into_arg()` picks `OsStrArg` if
+/// it applies, and falls back to `DisplayArg`.
+#[doc(hidden)]
+pub mod __private {
+    use std::{
+        ffi::{OsStr, OsString},
+        fmt,
+    };
+
+    pub struct Arg<T>(pub T);
+
+    pub trait OsStrArg {
+        fn __into_arg(&self) -> OsString;
+    }
+
+    impl<T: AsRef<OsStr>> OsStrArg for Arg<T> {
+        fn __into_arg(&self) -> OsString {
+            self.0.as_ref().to_os_string()
+        }
+    }
+
+    pub trait DisplayArg {
+        fn __into_arg(&self) -> OsString;
+    }
+
+    impl<T: fmt::Display> DisplayArg for &Arg<T> {
+        fn __into_arg(&self) -> OsString {
+            self.0.to_string().into()
+        }
+    }
+
+    /// Splits a plain command line, without interpolation or pipelines, the
+    /// way the proc-macro's tokenizer in `xshell-macros` does. Keep the two
+    /// in sync.
+    ///
+    /// Words end at ASCII whitespace or a single quote, a quoted string
+    /// extends to the next single quote, and tokens not separated by
+    /// whitespace are joined into one argument.
+    #[cfg(not(feature = "macros"))]
+    pub fn split(cmd: &str) -> crate::Cmd {
+        let mut words: Vec<String> = Vec::new();
+        let mut rest = cmd;
+        loop {
+            let trimmed = rest.trim_start();
+            let joined_to_prev = trimmed.len() == rest.len();
+            rest = trimmed;
+            if rest.is_empty() {
+                break;
+            }
+            let (len, text) = match rest.strip_prefix('\'') {
+                Some(quoted) => {
+                    let len = quoted.find('\'').unwrap();
+                    (len + 2, &quoted[..len])
+                }
+                None => {
+                    let len = rest
+                        .find(|it: char| it.is_ascii_whitespace() || it == '\'')
+                        .unwrap_or(rest.len());
+                    (len, &rest[..len])
+                }
+            };
+            match words.last_mut() {
+                Some(word) if joined_to_prev => word.push_str(text),
+                _ => words.push(text.to_string()),
+            }
+            rest = &rest[len..];
+        }
+        let mut words = words.into_iter();
+        crate::Cmd::new(words.next().unwrap()).args(words)
+    }
+
+    /// Whether `cmd` uses interpolation or pipelines, which the declarative
+    /// `cmd!` can't handle. Quoted `{` and `|` are literal, and so is a `|`
+    /// which is part of a word.
+    #[cfg(not(feature = "macros"))]
+    pub const fn needs_proc_macro(cmd: &str) -> bool {
+        let bytes = cmd.as_bytes();
+        let mut quoted = false;
+        let mut i = 0;
+        while i < bytes.len() {
+            let b = bytes[i];
+            if b == b'\'' {
+                quoted = !quoted;
+            } else if !quoted && b == b'{' {
+                return true;
+            } else if !quoted && b == b'|' {
+                let starts_word = i == 0 || is_separator(bytes[i - 1]);
+                let ends_word = i + 1 == bytes.len() || is_separator(bytes[i + 1]);
+                if starts_word && ends_word {
+                    return true;
+                }
+            }
+            i += 1;
+        }
+        false
+    }
+
+    /// Whether `cmd` has a program and balanced single quotes, which the
+    /// proc-macro would otherwise reject.
+    #[cfg(not(feature = "macros"))]
+    pub const fn is_well_formed(cmd: &str) -> bool {
+        let bytes = cmd.as_bytes();
+        let mut quoted = false;
+        let mut empty = true;
+        let mut i = 0;
+        while i < bytes.len() {
+            if bytes[i] == b'\'' {
+                quoted = !quoted;
+            }
+            if !bytes[i].is_ascii_whitespace() {
+                empty = false;
+            }
+            i += 1;
+        }
+        !quoted && !empty
+    }
+
+    #[cfg(not(feature = "macros"))]
+    const fn is_separator(b: u8) -> bool {
+        b.is_ascii_whitespace() || b == b'\'' || b == b'{'
+    }
+}
+
 #[must_use]
-#[derive(Debug)]
+#[derive(Clone)]
 pub struct Cmd {
     args: Vec<OsString>,
-    stdin_contents: Option<Vec<u8>>,
+    stdin_contents: Option<StdinSource>,
+    unlocked: bool,
+    #[cfg(unix)]
+    keep_fds: Vec<std::os::unix::io::RawFd>,
+    /// Subsequent stages of a pipeline, see [`Cmd::pipe`].
+    pipeline: Vec<Cmd>,
+    env_clear: bool,
+    /// Variables to set, or to remove if the value is `None`, in order.
+    env_changes: Vec<(OsString, Option<OsString>)>,
+    /// Variables of the parent to preserve after clearing, see
+    /// [`Cmd::hermetic_env`].
+    env_keep: Vec<OsString>,
+    current_dir: Option<PathBuf>,
+    ignore_status: bool,
+    ok_statuses: Vec<i32>,
+    echo: EchoTarget,
+    /// See [`Cmd::log_prefix`].
+    log_prefix: Option<String>,
+    timeout: Option<Duration>,
+    /// Number of attempts and delay between them, see [`Cmd::retry`].
+    retry: Option<(u32, Duration)>,
+    stdout_redirect: Option<Redirect>,
+    stderr_redirect: Option<Redirect>,
+    /// Indices of the `args` which are redacted when displayed, see
+    /// [`Cmd::secret_arg`].
+    secrets: Vec<usize>,
+}
+
+/// What to feed to the stdin of a command, see [`Cmd::stdin`] and
+/// [`Cmd::stdin_reader`].
+#[derive(Clone)]
+enum StdinSource {
+    Bytes(Vec<u8>),
+    /// Taken by the first run.
+    Reader(Arc<Mutex<Option<Box<dyn Read + Send>>>>),
+    Path(PathBuf),
+}
+
+impl fmt::Debug for StdinSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StdinSource::Bytes(bytes) => f.debug_tuple("Bytes").field(bytes).finish(),
+            StdinSource::Reader(_) => f.write_str("Reader(..)"),
+            StdinSource::Path(path) => f.debug_tuple("Path").field(path).finish(),
+        }
+    }
+}
+
+/// A file to write the output of a command to, see [`Cmd::stdout_path`].
+#[derive(Debug, Clone)]
+struct Redirect {
+    path: PathBuf,
+    append: bool,
+}
+
+impl Redirect {
+    fn open(&self) -> io::Result<File> {
+        let mut options = std::fs::OpenOptions::new();
+        if self.append {
+            options.append(true);
+        } else {
+            options.write(true).truncate(true);
+        }
+        open_redirect(&self.path, options.create(true).open(&self.path))
+    }
 }
 
+const SECRET: &str = "<secret>";
+
 impl fmt::Display for Cmd {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let mut space = "";
-        for arg in &self.args {
+        for arg in self.redacted_args() {
             write!(f, "{}", space)?;
             space = " ";
 
-            let arg = arg.to_string_lossy();
+            let arg = match arg {
+                Some(arg) => arg.to_string_lossy(),
+                None => {
+                    write!(f, "{}", SECRET)?;
+                    continue;
+                }
+            };
             if arg.chars().any(|it| it.is_ascii_whitespace()) {
                 write!(f, "\"{}\"", arg.escape_default())?
             } else {
                 write!(f, "{}", arg)?
             };
         }
+        for stage in &self.pipeline {
+            write!(f, " | {}", stage)?;
+        }
         Ok(())
     }
 }
 
+/// Like a derived impl, but with the secret arguments redacted, as in the
+/// `Display` impl.
+impl fmt::Debug for Cmd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let args = self
+            .redacted_args()
+            .map(|arg| arg.map_or(OsStr::new(SECRET), |it| it.as_os_str()))
+            .collect::<Vec<_>>();
+        let mut res = f.debug_struct("Cmd");
+        res.field("args", &args)
+            .field("stdin_contents", &self.stdin_contents)
+            .field("unlocked", &self.unlocked);
+        #[cfg(unix)]
+        res.field("keep_fds", &self.keep_fds);
+        res.field("pipeline", &self.pipeline)
+            .field("env_clear", &self.env_clear)
+            .field("env_changes", &self.env_changes)
+            .field("env_keep", &self.env_keep)
+            .field("current_dir", &self.current_dir)
+            .field("ignore_status", &self.ignore_status)
+            .field("ok_statuses", &self.ok_statuses)
+            .field("echo", &self.echo)
+            .field("log_prefix", &self.log_prefix)
+            .field("timeout", &self.timeout)
+            .field("retry", &self.retry)
+            .field("stdout_redirect", &self.stdout_redirect)
+            .field("stderr_redirect", &self.stderr_redirect)
+            .field("secrets", &self.secrets)
+            .finish()
+    }
+}
+
+impl Cmd {
+    /// The arguments, with `None` in place of the ones set with
+    /// [`Cmd::secret_arg`].
+    fn redacted_args(&self) -> impl Iterator<Item = Option<&OsString>> {
+        let args = self.args.iter().enumerate();
+        args.map(move |(i, arg)| Some(arg).filter(|_| !self.secrets.contains(&i)))
+    }
+
+    /// Renders the command so that it can be pasted into a POSIX shell.
+    ///
+    /// Unlike the `Display` impl, which is meant for humans, every argument
+    /// with characters special to the shell is wrapped in single quotes, with
+    /// embedded single quotes written as `'\''`.
+    ///
+    /// ```
+    /// # use xshell::cmd;
+    /// # #[cfg(feature = "macros")] {
+    /// let msg = "it's $5";
+    /// let cmd = cmd!("git commit -m {msg}");
+    /// assert_eq!(cmd.to_sh_string(), r"git commit -m 'it'\''s $5'");
+    /// # }
+    /// ```
+    pub fn to_sh_string(&self) -> String {
+        self.render(&|_i, arg| {
+            let plain = !arg.is_empty()
+                && arg.chars().all(|c| {
+                    c.is_ascii_alphanumeric()
+                        || matches!(c, '-' | '_' | '.' | '/' | ':' | '=' | ',' | '+' | '@' | '%')
+                });
+            if plain {
+                arg.to_string()
+            } else {
+                format!("'{}'", arg.replace('\'', r"'\''"))
+            }
+        })
+    }
+
+    /// Renders the command so that it can be pasted into PowerShell.
+    ///
+    /// Arguments with special characters are wrapped in single quotes, with
+    /// embedded single quotes doubled. A quoted program is prefixed with the
+    /// `&` call operator. Use [`Cmd::to_cmd_string`] for `cmd.exe` and
+    /// [`Cmd::to_sh_string`] for POSIX shells.
+    ///
+    /// Requires the `extra-cmd` feature.
+    #[cfg(feature = "extra-cmd")]
+    pub fn to_powershell_string(&self) -> String {
+        fn quote(arg: &str) -> Option<String> {
+            let plain = !arg.is_empty()
+                && arg.chars().all(|c| {
+                    c.is_ascii_alphanumeric()
+                        || matches!(c, '-' | '_' | '.' | '/' | '\\' | ':' | '=')
+                });
+            if plain {
+                None
+            } else {
+                Some(format!("'{}'", arg.replace('\'', "''")))
+            }
+        }
+        self.render(&|i, arg| match quote(arg) {
+            Some(quoted) if i == 0 => format!("& {}", quoted),
+            Some(quoted) => quoted,
+            None => arg.to_string(),
+        })
+    }
+
+    /// Renders the command so that it can be pasted into `cmd.exe`.
+    ///
+    /// Arguments with whitespace or double quotes are wrapped in double
+    /// quotes, with embedded double quotes doubled. Otherwise, the characters
+    /// special to `cmd.exe` are escaped with `^`. `%` can't be escaped
+    /// reliably in interactive `cmd.exe`, and is left as is.
+    ///
+    /// Requires the `extra-cmd` feature.
+    #[cfg(feature = "extra-cmd")]
+    pub fn to_cmd_string(&self) -> String {
+        self.render(&|_i, arg| {
+            if arg.is_empty() || arg.contains(|c: char| c.is_whitespace() || c == '"') {
+                format!("\"{}\"", arg.replace('"', "\"\""))
+            } else {
+                let mut res = String::new();
+                for c in arg.chars() {
+                    if matches!(c, '&' | '|' | '<' | '>' | '^' | '(' | ')') {
+                        res.push('^');
+                    }
+                    res.push(c);
+                }
+                res
+            }
+        })
+    }
+
+    /// Renders every stage with `quote`, which also receives the index of the
+    /// argument within its stage.
+    fn render(&self, quote: &dyn Fn(usize, &str) -> String) -> String {
+        let mut res = String::new();
+        for (stage_index, stage) in self.stages().enumerate() {
+            if stage_index > 0 {
+                res.push_str(" | ");
+            }
+            for (i, arg) in stage.redacted_args().enumerate() {
+                if i > 0 {
+                    res.push(' ');
+                }
+                match arg {
+                    Some(arg) => res.push_str(&quote(i, &arg.to_string_lossy())),
+                    None => res.push_str(SECRET),
+                }
+            }
+        }
+        res
+    }
+}
+
+/// Runs a freshly built command in each of `dirs`, collecting the results.
+///
+/// A failure in one directory doesn't prevent running in the rest. Each
+/// command is echoed after the directory it runs in, as `$ cd dir`, with the
+/// same [`Cmd::echo_to`] target, so nothing is echoed for a quiet command.
+///
+/// ```no_run
+/// # use std::path::PathBuf;
+/// use xshell::{cmd, run_in_dirs};
+///
+/// let crates = [PathBuf::from("xshell-macros"), PathBuf::from(".")];
+/// for res in run_in_dirs(&crates, || cmd!("cargo test")) {
+///     res?;
+/// }
+/// # Ok::<(), xshell::Error>(())
+/// ```
+///
+/// Requires the `extra-cmd` feature.
+#[cfg(feature = "extra-cmd")]
+pub fn run_in_dirs(dirs: &[PathBuf], make_cmd: impl Fn() -> Cmd) -> Vec<Result<()>> {
+    dirs.iter()
+        .map(|dir| {
+            let cmd = make_cmd();
+            echo::echo_cd(&cmd, dir);
+            cmd.current_dir(dir).run()
+        })
+        .collect()
+}
+
+/// A list of commands to run one after another, without stopping at the first
+/// failure.
+///
+/// ```no_run
+/// use xshell::{cmd, Batch};
+///
+/// # #[cfg(feature = "macros")] {
+/// let mut batch = Batch::new();
+/// for file in ["a.sh", "b.sh"] {
+///     batch = batch.cmd(cmd!("shellcheck {file}"));
+/// }
+/// let failed = batch.run_all_collect().into_iter().filter(|(_cmd, res)| res.is_err()).count();
+/// assert_eq!(failed, 0);
+/// # }
+/// ```
+///
+/// Requires the `extra-cmd` feature.
+#[cfg(feature = "extra-cmd")]
+#[must_use]
+#[derive(Debug, Default)]
+pub struct Batch {
+    cmds: Vec<Cmd>,
+}
+
+#[cfg(feature = "extra-cmd")]
+impl Batch {
+    pub fn new() -> Batch {
+        Batch::default()
+    }
+
+    /// Adds `cmd` to the end of the batch.
+    pub fn cmd(mut self, cmd: Cmd) -> Batch {
+        self.cmds.push(cmd);
+        self
+    }
+
+    /// Runs every command in order, and returns each command together with its
+    /// result.
+    ///
+    /// Failures don't stop the batch. If any command fails, a summary of the
+    /// failures is printed to stderr at the end.
+    pub fn run_all_collect(self) -> Vec<(Cmd, Result<()>)> {
+        let res = self
+            .cmds
+            .into_iter()
+            .map(|cmd| {
+                let res = cmd.clone().run();
+                (cmd, res)
+            })
+            .collect::<Vec<_>>();
+        let failures = res.iter().filter_map(|(_cmd, res)| res.as_ref().err()).collect::<Vec<_>>();
+        if !failures.is_empty() {
+            eprintln!("{} of {} commands failed:", failures.len(), res.len());
+            for err in failures {
+                eprintln!("  {}", err);
+            }
+        }
+        res
+    }
+}
+
 impl From<Cmd> for std::process::Command {
     fn from(cmd: Cmd) -> Self {
         cmd.command()
@@ -274,7 +904,121 @@ impl Cmd {
         Cmd::_new(program.as_ref())
     }
     fn _new(program: &Path) -> Cmd {
-        Cmd { args: vec![program.as_os_str().to_owned()], stdin_contents: None }
+        Cmd {
+            args: vec![program.as_os_str().to_owned()],
+            stdin_contents: None,
+            unlocked: false,
+            #[cfg(unix)]
+            keep_fds: Vec::new(),
+            pipeline: Vec::new(),
+            env_clear: false,
+            env_changes: Vec::new(),
+            env_keep: Vec::new(),
+            current_dir: None,
+            ignore_status: false,
+            ok_statuses: Vec::new(),
+            echo: EchoTarget::Stdout,
+            log_prefix: None,
+            timeout: None,
+            retry: None,
+            stdout_redirect: None,
+            stderr_redirect: None,
+            secrets: Vec::new(),
+        }
+    }
+
+    /// Splits `line` into a program and arguments, like a POSIX shell does.
+    ///
+    /// Whitespace separates arguments, unless quoted. Single quotes keep
+    /// everything literally, double quotes allow escaping `"`, `\` and `$`
+    /// with a backslash, and outside of quotes a backslash escapes any
+    /// character. This is for command lines coming from configs and such:
+    /// it's not a shell, so there's no globbing, expansion of variables or
+    /// pipes. Prefer [`cmd!`] for commands known at compile time.
+    ///
+    /// Unbalanced quotes are an error.
+    ///
+    /// ```
+    /// # use xshell::Cmd;
+    /// let cmd = Cmd::parse(r#"git commit -m "fix: don't panic""#)?;
+    /// assert_eq!(cmd.get_program(), "git");
+    /// assert_eq!(cmd.get_args(), ["commit", "-m", "fix: don't panic"]);
+    /// # Ok::<(), xshell::Error>(())
+    /// ```
+    ///
+    /// Requires the `extra-cmd` feature.
+    #[cfg(feature = "extra-cmd")]
+    pub fn parse(line: &str) -> Result<Cmd> {
+        let mut words = Vec::new();
+        let mut word: Option<String> = None;
+        let mut chars = line.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                c if c.is_whitespace() => words.extend(word.take()),
+                '\'' => {
+                    let word = word.get_or_insert_with(String::new);
+                    loop {
+                        match chars.next() {
+                            Some('\'') => break,
+                            Some(c) => word.push(c),
+                            None => return Err(cmd_line_err(line, "unbalanced single quote")),
+                        }
+                    }
+                }
+                '"' => {
+                    let word = word.get_or_insert_with(String::new);
+                    loop {
+                        match chars.next() {
+                            Some('"') => break,
+                            Some('\\') => match chars.next() {
+                                Some(c @ ('"' | '\\' | '$' | '`')) => word.push(c),
+                                Some(c) => {
+                                    word.push('\\');
+                                    word.push(c);
+                                }
+                                None => return Err(cmd_line_err(line, "unbalanced double quote")),
+                            },
+                            Some(c) => word.push(c),
+                            None => return Err(cmd_line_err(line, "unbalanced double quote")),
+                        }
+                    }
+                }
+                '\\' => match chars.next() {
+                    Some(c) => word.get_or_insert_with(String::new).push(c),
+                    None => return Err(cmd_line_err(line, "trailing backslash")),
+                },
+                c => word.get_or_insert_with(String::new).push(c),
+            }
+        }
+        words.extend(word);
+        let mut words = words.into_iter();
+        match words.next() {
+            Some(program) => Ok(Cmd::new(program).args(words)),
+            None => Err(cmd_line_err(line, "empty command line")),
+        }
+    }
+
+    /// Creates a command which runs `script` with the system shell: `sh -c`
+    /// on Unix, `cmd /C` on Windows.
+    ///
+    /// This is the escape hatch for when you really need pipes, redirections
+    /// or globs. Nothing about the script is portable: `sh` and `cmd.exe`
+    /// have completely different syntax and quoting rules, and interpolating
+    /// untrusted values into `script` is a shell injection. Prefer [`cmd!`]
+    /// whenever possible.
+    ///
+    /// ```
+    /// # use xshell::Cmd;
+    /// let out = Cmd::shell("echo hello").read()?;
+    /// assert_eq!(out.trim(), "hello");
+    /// # Ok::<(), xshell::Error>(())
+    /// ```
+    pub fn shell(script: &str) -> Cmd {
+        if cfg!(windows) {
+            Cmd::new("cmd").arg("/C").arg(script)
+        } else {
+            Cmd::new("sh").arg("-c").arg(script)
+        }
     }
 
     pub fn arg(mut self, arg: impl AsRef<OsStr>) -> Cmd {
@@ -289,142 +1033,1853 @@ impl Cmd {
         args.into_iter().for_each(|it| self._arg(it.as_ref()));
         self
     }
+    /// Appends `arg` if it is `Some`, does nothing otherwise.
+    pub fn arg_opt(mut self, arg: Option<impl AsRef<OsStr>>) -> Cmd {
+        if let Some(arg) = &arg {
+            self._arg(arg.as_ref());
+        }
+        self
+    }
+    /// Appends `arg` if `cond` is true, does nothing otherwise.
+    ///
+    /// ```
+    /// # use xshell::cmd;
+    /// let release = true;
+    /// let cmd = cmd!("cargo build").arg_if(release, "--release");
+    /// assert_eq!(cmd.to_string(), "cargo build --release");
+    /// ```
+    pub fn arg_if(self, cond: bool, arg: impl AsRef<OsStr>) -> Cmd {
+        if cond {
+            self.arg(arg)
+        } else {
+            self
+        }
+    }
+    /// Appends `args` if `cond` is true, does nothing otherwise.
+    pub fn args_if<I>(self, cond: bool, args: I) -> Cmd
+    where
+        I: IntoIterator,
+        I::Item: AsRef<OsStr>,
+    {
+        if cond {
+            self.args(args)
+        } else {
+            self
+        }
+    }
+    /// Appends `arg`, which is shown as `<secret>` when the command is echoed
+    /// or displayed, including in error messages.
+    ///
+    /// ```
+    /// # use xshell::cmd;
+    /// let cmd = cmd!("curl -H").secret_arg("Authorization: Bearer hunter2");
+    /// assert_eq!(cmd.to_string(), "curl -H <secret>");
+    /// ```
+    pub fn secret_arg(mut self, arg: impl AsRef<OsStr>) -> Cmd {
+        self._arg(arg.as_ref());
+        self.secrets.push(self.args.len() - 1);
+        self
+    }
+    /// Appends each line of the file at `path` as a separate argument.
+    ///
+    /// Line endings are stripped, but other whitespace is kept. Blank lines
+    /// and lines starting with `#` are skipped.
+    pub fn args_file(mut self, path: impl AsRef<Path>) -> Result<Cmd> {
+        let text = read_file(path)?;
+        text.lines()
+            .filter(|line| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+            .for_each(|line| self._arg(line.as_ref()));
+        Ok(self)
+    }
+    /// Returns the program which will be executed.
+    pub fn get_program(&self) -> &OsStr {
+        &self.args[0]
+    }
+    /// Returns the arguments which will be passed to the program.
+    pub fn get_args(&self) -> &[OsString] {
+        &self.args[1..]
+    }
     fn _arg(&mut self, arg: &OsStr) {
         self.args.push(arg.to_owned())
     }
 
+    /// Appends `suffix` to the last argument, or to the program if there are
+    /// no arguments yet, like `{a}{b}` does in [`cmd!`].
+    pub fn extend_last_arg(mut self, suffix: impl AsRef<OsStr>) -> Cmd {
+        self.___extend_arg(suffix.as_ref());
+        self
+    }
+
     #[doc(hidden)]
     pub fn __extend_arg(mut self, arg: impl AsRef<OsStr>) -> Cmd {
         self.___extend_arg(arg.as_ref());
         self
     }
     fn ___extend_arg(&mut self, arg: &OsStr) {
-        self.args.last_mut().unwrap().push(arg)
+        match self.args.last_mut() {
+            Some(last) => last.push(arg),
+            None => self.args.push(arg.to_owned()),
+        }
     }
 
-    pub fn stdin(mut self, stdin: impl AsRef<[u8]>) -> Cmd {
-        self._stdin(stdin.as_ref());
+    /// Connects stdout of this command to stdin of `next`, like `|` in the
+    /// shell.
+    ///
+    /// Pipelines are supported by [`Cmd::run`], by [`Cmd::read`] and the other
+    /// methods which read stdout, like [`Cmd::read_lines`] and
+    /// [`Cmd::output`]. The stages inherit stderr, so the methods which
+    /// capture it, like [`Cmd::read_combined`] and `Cmd::run_full`, return an
+    /// error instead.
+    ///
+    /// All stages must succeed, otherwise the error names the first stage
+    /// which failed. Stages other than the last one are allowed to die from
+    /// `SIGPIPE`, which happens when a later stage doesn't read all of its
+    /// input.
+    ///
+    /// ```
+    /// # use xshell::cmd;
+    /// let out = cmd!("echo hello").pipe(cmd!("tr a-z A-Z")).read()?;
+    /// assert_eq!(out, "HELLO");
+    /// # Ok::<(), xshell::Error>(())
+    /// ```
+    pub fn pipe(mut self, mut next: Cmd) -> Cmd {
+        let rest = std::mem::take(&mut next.pipeline);
+        self.pipeline.push(next);
+        self.pipeline.extend(rest);
         self
     }
-    fn _stdin(&mut self, stdin: &[u8]) {
-        self.stdin_contents = Some(stdin.to_vec());
-    }
 
-    pub fn read(self) -> Result<String> {
-        {
-            let s = Self::mrun(&self.args).unwrap();
-            return Ok(s);
+    /// Spawns the command, or every stage of the pipeline, and passes the
+    /// stdout and stderr pipes of the last one to `read`, which returns
+    /// whether it has read enough and the command should be killed.
+    ///
+    /// Then waits for all of them, killing them once the [`Cmd::timeout`]
+    /// expires, or as soon as `read` fails. The stages of a pipeline inherit
+    /// stderr. Every stage but the last must succeed, or die from `SIGPIPE`
+    /// when a later one doesn't read all of its input; the status of the last
+    /// one is returned for [`Cmd::check_status`].
+    fn exec(
+        &self,
+        stdin: Stdio,
+        stdout: Stdio,
+        stderr: Stdio,
+        read: &mut dyn FnMut(Option<ChildStdout>, Option<ChildStderr>) -> io::Result<bool>,
+    ) -> Result<ExitStatus, CmdErrorKind> {
+        let mut children = Vec::new();
+        if let Err((index, io_err)) = self.spawn_stages(stdin, stdout, stderr, &mut children) {
+            // Wait for every spawned stage, even if spawning a later one failed.
+            for mut child in children {
+                let _ = child.wait();
+            }
+            if self.pipeline.is_empty() {
+                return Err(CmdErrorKind::Io(io_err));
+            }
+            let program = self.stages().nth(index).unwrap().args[0].to_string_lossy().into_owned();
+            return Err(CmdErrorKind::PipelineSpawn { index, program, io_err });
         }
+        let last = children.last_mut().unwrap();
+        let (stdout, stderr) = (last.stdout.take(), last.stderr.take());
 
-        match self.read_raw() {
-            Ok(output) if output.status.success() => {
-                let mut stdout = String::from_utf8(output.stdout)
-                    .map_err(|utf8_err| CmdErrorKind::NonUtf8Stdout(utf8_err).err(self))?;
-                if stdout.ends_with('\n') {
-                    stdout.pop();
-                }
-
-                Ok(stdout)
+        let kill = AtomicBool::new(false);
+        let (res, statuses) = match self.timeout {
+            None => {
+                let res = read(stdout, stderr);
+                kill.store(!matches!(res, Ok(false)), Ordering::SeqCst);
+                (res, wait_all(children, None, &kill))
+            }
+            // The output is read on this thread, so it can borrow from the
+            // caller, while another one enforces the timeout.
+            Some(timeout) => {
+                let kill = Arc::new(kill);
+                let waiter = spawn_thread({
+                    let kill = Arc::clone(&kill);
+                    move || wait_all(children, Some(timeout), &kill)
+                });
+                let res = read(stdout, stderr);
+                kill.store(!matches!(res, Ok(false)), Ordering::SeqCst);
+                (res, waiter.join().unwrap())
+            }
+        };
+        let killed = res.map_err(CmdErrorKind::Io)?;
+        let statuses = statuses.map_err(CmdErrorKind::Io)?;
+        let (last, rest) = statuses.split_last().unwrap();
+        // Killing the last stage might make the others fail too.
+        if killed {
+            return Ok(*last);
+        }
+        for (index, (stage, status)) in self.stages().zip(rest).enumerate() {
+            if !stage.status_ok(status) && !is_sigpipe(status) {
+                let program = stage.args[0].to_string_lossy().into_owned();
+                return Err(CmdErrorKind::PipelineStage { index, program, status: *status });
             }
-            Ok(output) => Err(CmdErrorKind::NonZeroStatus(output.status).err(self)),
-            Err(io_err) => Err(CmdErrorKind::Io(io_err).err(self)),
         }
+        Ok(*last)
     }
-    fn read_raw(&self) -> io::Result<Output> {
-        let mut child = self
-            .command()
-            .stdin(match &self.stdin_contents {
-                Some(_) => Stdio::piped(),
-                None => Stdio::null(),
-            })
-            .stderr(Stdio::piped())
-            .stdout(Stdio::piped())
-            .spawn()?;
-
-        if let Some(stdin_contents) = &self.stdin_contents {
-            let mut stdin = child.stdin.take().unwrap();
-            stdin.write_all(stdin_contents)?;
-            stdin.flush()?;
+    /// Spawns the stages, reporting the index of the stage which failed to
+    /// spawn, if any.
+    fn spawn_stages(
+        &self,
+        stdin: Stdio,
+        stdout: Stdio,
+        stderr: Stdio,
+        children: &mut Vec<Child>,
+    ) -> Result<(), (usize, io::Error)> {
+        if self.pipeline.is_empty() {
+            let child = self.spawn_piped(stdin, stdout, stderr).map_err(|it| (0, it))?;
+            children.push(child);
+            return Ok(());
+        }
+        let first = self.spawn_piped(stdin, Stdio::piped(), Stdio::inherit());
+        children.push(first.map_err(|it| (0, it))?);
+        let mut stdout = Some(stdout);
+        for (i, stage) in self.pipeline.iter().enumerate() {
+            let stdin = children.last_mut().unwrap().stdout.take().unwrap();
+            let stage_stdout = match &self.stdout_redirect {
+                _ if i + 1 < self.pipeline.len() => Stdio::piped(),
+                Some(redirect) => Stdio::from(redirect.open().map_err(|it| (i + 1, it))?),
+                None => stdout.take().unwrap(),
+            };
+            let child = stage
+                .spawn_command(stage.command().stdin(stdin).stdout(stage_stdout))
+                .map_err(|it| (i + 1, it))?;
+            children.push(child);
         }
-        child.wait_with_output()
+        Ok(())
     }
-
-    pub fn run(self) -> Result<()> {
-        println!("$ {}", self);
-        match self.command().status() {
-            Ok(status) if status.success() => Ok(()),
-            Ok(status) => Err(CmdErrorKind::NonZeroStatus(status).err(self)),
-            Err(io_err) => Err(CmdErrorKind::Io(io_err).err(self)),
+    fn stages(&self) -> impl Iterator<Item = &Cmd> {
+        std::iter::once(self).chain(&self.pipeline)
+    }
+    /// Returns an error for the methods which can't run a pipeline, because
+    /// they capture stderr.
+    fn check_not_pipeline(&self) -> io::Result<()> {
+        if self.pipeline.is_empty() {
+            return Ok(());
         }
+        Err(io::Error::new(io::ErrorKind::Other, "can't capture stderr of a pipeline"))
     }
 
-    fn command(&self) -> std::process::Command {
-        let mut res = std::process::Command::new(&self.args[0]);
-        res.args(&self.args[1..]);
-        res
+    /// Doesn't echo the command before running it.
+    pub fn quiet(self) -> Cmd {
+        self.echo_to(EchoTarget::None)
+    }
+    /// Prefixes every line of output of [`Cmd::run`], and the echo of the
+    /// command, with `prefix`, to tell apart the output of commands running
+    /// concurrently.
+    ///
+    /// The output is captured and printed line by line, so the command no
+    /// longer writes to a terminal. Doesn't apply to pipelines.
+    ///
+    /// ```
+    /// # use xshell::cmd;
+    /// cmd!("echo hello").log_prefix("[build] ").run()?; // prints `[build] hello`
+    /// # Ok::<(), xshell::Error>(())
+    /// ```
+    pub fn log_prefix(mut self, prefix: impl Into<String>) -> Cmd {
+        self.log_prefix = Some(prefix.into());
+        self
+    }
+    /// Sets where the command is echoed before running it, stdout by default.
+    ///
+    /// Echoing to stderr keeps stdout clean when the output of the script
+    /// itself is piped somewhere.
+    pub fn echo_to(mut self, dst: EchoTarget) -> Cmd {
+        self.echo = dst;
+        self
     }
 
-    fn mrun(cmd: &[std::ffi::OsString]) -> std::io::Result<String> {
-        use std::io::Read;
-        use std::process;
-
-        let cmd: Vec<&str> = cmd.iter().map(|c| c.to_str().unwrap()).collect();
-        let cmd = &cmd;
+    /// Treats any exit status as success, for commands whose exit code is
+    /// informational.
+    pub fn ignore_status(mut self) -> Cmd {
+        self.ignore_status = true;
+        self
+    }
+    /// Treats the exit codes in `codes` as success, in addition to zero. See
+    /// [`exit_code`] for named codes.
+    ///
+    /// ```
+    /// # use xshell::cmd;
+    /// // `grep` exits with 1 if nothing matched.
+    /// let matches = cmd!("grep TODO Cargo.toml").ok_statuses(&[1]).read()?;
+    /// assert_eq!(matches, "");
+    /// # Ok::<(), xshell::Error>(())
+    /// ```
+    pub fn ok_statuses(mut self, codes: &[i32]) -> Cmd {
+        self.ok_statuses.extend_from_slice(codes);
+        self
+    }
+    fn status_ok(&self, status: &ExitStatus) -> bool {
+        status.success()
+            || self.ignore_status
+            || status.code().map_or(false, |code| self.ok_statuses.contains(&code))
+    }
+    /// Checks the exit status reported alongside `value`, which is the one of
+    /// the last stage for a pipeline.
+    fn check_status<T>(
+        &self,
+        res: Result<(ExitStatus, T), CmdErrorKind>,
+    ) -> Result<T, CmdErrorKind> {
+        let (status, value) = res?;
+        let index = self.pipeline.len();
+        let last = self.stages().last().unwrap();
+        if last.status_ok(&status) {
+            Ok(value)
+        } else if index == 0 {
+            Err(CmdErrorKind::NonZeroStatus(status))
+        } else {
+            let program = last.args[0].to_string_lossy().into_owned();
+            Err(CmdErrorKind::PipelineStage { index, program, status })
+        }
+    }
 
-        let mut stdin = None;
+    /// Kills the command if it doesn't finish within `dur`.
+    ///
+    /// Applies to every method which runs the command to completion, from
+    /// [`Cmd::run`] and [`Cmd::read`] to `Cmd::run_stdout`, but not to
+    /// [`Cmd::spawn`]. All stages of a pipeline are killed. The killed command
+    /// is reaped, and the error reports how long it ran.
+    ///
+    /// ```
+    /// # use std::time::Duration;
+    /// # use xshell::cmd;
+    /// let err = cmd!("sleep 10").timeout(Duration::from_millis(100)).run().unwrap_err();
+    /// assert!(err.to_string().starts_with("command `sleep 10` timed out after"));
+    /// ```
+    pub fn timeout(mut self, dur: Duration) -> Cmd {
+        self.timeout = Some(dur);
+        self
+    }
+    /// Makes the methods which run the command to completion try it up to
+    /// `attempts` times, waiting `delay` after each failure, for flaky
+    /// commands like network requests.
+    ///
+    /// A failure is anything that would be an error, so statuses accepted by
+    /// [`Cmd::ignore_status`] and [`Cmd::ok_statuses`] are not retried. The
+    /// methods which return the exit status instead of checking it,
+    /// `Cmd::run_full`, `Cmd::run_stdout` and `Cmd::run_split_streaming`,
+    /// run the command once, as does [`Cmd::spawn`].
+    ///
+    /// The error of the last attempt is returned. Retries are echoed with the
+    /// attempt number. Input set with [`Cmd::stdin_reader`] is consumed by the
+    /// first attempt.
+    ///
+    /// ```no_run
+    /// # use std::time::Duration;
+    /// # use xshell::cmd;
+    /// cmd!("cargo publish").retry(3, Duration::from_secs(10)).run()?;
+    /// # Ok::<(), xshell::Error>(())
+    /// ```
+    pub fn retry(mut self, attempts: u32, delay: Duration) -> Cmd {
+        self.retry = Some((attempts, delay));
+        self
+    }
+    /// Runs the command in `dir`, instead of the current directory.
+    ///
+    /// A relative `dir` is resolved against the current directory, including
+    /// the one set by an active [`pushd`], when the command is spawned.
+    pub fn current_dir(mut self, dir: impl AsRef<Path>) -> Cmd {
+        self.current_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
 
-        let runit = |stdin: Option<process::Child>,
-                     stdout: process::Stdio,
-                     cmd: &[&str]|
-         -> Option<process::Child> {
-            if cmd.is_empty() {
-                return None;
-            }
+    /// Sets the environment variable `key` for this command only.
+    ///
+    /// Unlike [`pushenv`], this doesn't touch the environment of the current
+    /// process.
+    pub fn env(mut self, key: impl AsRef<OsStr>, val: impl AsRef<OsStr>) -> Cmd {
+        self._env(key.as_ref(), Some(val.as_ref()));
+        self
+    }
+    /// Sets several environment variables for this command only.
+    pub fn envs<I, K, V>(mut self, vars: I) -> Cmd
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<OsStr>,
+        V: AsRef<OsStr>,
+    {
+        vars.into_iter().for_each(|(k, v)| self._env(k.as_ref(), Some(v.as_ref())));
+        self
+    }
+    /// Removes the environment variable `key` for this command only.
+    pub fn env_remove(mut self, key: impl AsRef<OsStr>) -> Cmd {
+        self._env(key.as_ref(), None);
+        self
+    }
+    fn _env(&mut self, key: &OsStr, val: Option<&OsStr>) {
+        self.env_changes.push((key.to_owned(), val.map(OsStr::to_owned)));
+    }
 
-            let mut cmd = cmd.iter();
+    /// Runs the command with an empty environment, except for the variables
+    /// set with [`Cmd::env`] and [`Cmd::envs`], before or after this call.
+    ///
+    /// Nothing is inherited, not even `PATH`, see [`Cmd::hermetic_env`] to
+    /// keep a few variables. The program itself is still looked up in the
+    /// current `PATH`.
+    pub fn env_clear(mut self) -> Cmd {
+        self.env_clear = true;
+        self.env_keep.clear();
+        self
+    }
 
-            let stdin = if let Some(stdin) = stdin { stdin.stdout } else { None };
+    /// Runs the command with an empty environment, except for the variables
+    /// in `keep` and a minimal set required to spawn programs.
+    ///
+    /// `PATH` is always preserved. On Windows, so are `SystemRoot` and
+    /// `windir`, without which many programs fail to start.
+    ///
+    /// ```
+    /// # use xshell::cmd;
+    /// let cmd = cmd!("cargo build").hermetic_env(&["HOME", "CARGO_HOME", "RUSTUP_HOME"]);
+    /// ```
+    pub fn hermetic_env(mut self, keep: &[&str]) -> Cmd {
+        const ALWAYS_KEEP: &[&str] =
+            if cfg!(windows) { &["PATH", "SystemRoot", "windir"] } else { &["PATH"] };
+        self.env_clear = true;
+        self.env_changes.clear();
+        self.env_keep.clear();
+        for name in ALWAYS_KEEP.iter().chain(keep) {
+            self.env_keep.push(name.into());
+        }
+        self
+    }
 
-            if let Some(stdin) = stdin {
-                if let Ok(child) = process::Command::new(cmd.next()?)
-                    .args(&cmd.collect::<Vec<&&str>>())
-                    .stdin(stdin)
-                    .stdout(stdout)
-                    .spawn()
-                {
-                    Some(child)
-                } else {
-                    None
+    pub fn stdin(mut self, stdin: impl AsRef<[u8]>) -> Cmd {
+        self._stdin(stdin.as_ref());
+        self
+    }
+    fn _stdin(&mut self, stdin: &[u8]) {
+        self.stdin_contents = Some(StdinSource::Bytes(stdin.to_vec()));
+    }
+    /// Streams the contents of `reader` to the command's stdin, without
+    /// buffering all of it in memory first.
+    ///
+    /// The reader is consumed by the first run: clones of the command share it,
+    /// and get an empty stdin once it's gone.
+    ///
+    /// ```
+    /// # use xshell::cmd;
+    /// let file = std::fs::File::open("Cargo.toml")?;
+    /// let n_lines = cmd!("wc -l").stdin_reader(file).read()?;
+    /// assert!(n_lines.trim().parse::<u32>().unwrap() > 0);
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn stdin_reader(mut self, reader: impl Read + Send + 'static) -> Cmd {
+        let reader: Box<dyn Read + Send> = Box::new(reader);
+        self.stdin_contents = Some(StdinSource::Reader(Arc::new(Mutex::new(Some(reader)))));
+        self
+    }
+    /// Connects the file at `path` to the command's stdin, like `< path` in
+    /// the shell.
+    ///
+    /// The file is opened when the command is spawned.
+    pub fn stdin_path(mut self, path: impl AsRef<Path>) -> Cmd {
+        self.stdin_contents = Some(StdinSource::Path(path.as_ref().to_path_buf()));
+        self
+    }
+
+    /// Writes the command's stdout to the file at `path`, like `> path` in the
+    /// shell.
+    ///
+    /// The file is created or truncated when the command is spawned. For a
+    /// pipeline, this redirects the stdout of the last stage. Reading the
+    /// stdout of a redirected command, with [`Cmd::read`] and the like, is an
+    /// error.
+    ///
+    /// ```
+    /// # use xshell::{cmd, read_file, with_temp_dir};
+    /// with_temp_dir(|dir| {
+    ///     let log = dir.join("out.log");
+    ///     cmd!("echo hello").stdout_path(&log).run()?;
+    ///     assert_eq!(read_file(&log)?, "hello\n");
+    ///     Ok(())
+    /// })?;
+    /// # Ok::<(), xshell::Error>(())
+    /// ```
+    pub fn stdout_path(mut self, path: impl AsRef<Path>) -> Cmd {
+        self.stdout_redirect = Some(Redirect { path: path.as_ref().to_path_buf(), append: false });
+        self
+    }
+    /// Like [`Cmd::stdout_path`], but appends to the file, like `>> path`.
+    pub fn stdout_path_append(mut self, path: impl AsRef<Path>) -> Cmd {
+        self.stdout_redirect = Some(Redirect { path: path.as_ref().to_path_buf(), append: true });
+        self
+    }
+    /// Like [`Cmd::stdout_path`], but for stderr, like `2> path`.
+    pub fn stderr_path(mut self, path: impl AsRef<Path>) -> Cmd {
+        self.stderr_redirect = Some(Redirect { path: path.as_ref().to_path_buf(), append: false });
+        self
+    }
+    /// Errors if the stream which is about to be captured is redirected to a
+    /// file instead.
+    fn check_not_redirected(&self, stdout: bool) -> io::Result<()> {
+        let (name, redirect) = if stdout {
+            ("stdout", &self.stdout_redirect)
+        } else {
+            ("stderr", &self.stderr_redirect)
+        };
+        match redirect {
+            Some(redirect) => {
+                let msg = format!(
+                    "{} is redirected to `{}`, can't read it",
+                    name,
+                    redirect.path.display()
+                );
+                Err(io::Error::new(io::ErrorKind::Other, msg))
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Runs the command without ever taking the global shell lock.
+    ///
+    /// Spawning doesn't take the lock anyway, but reading the state of the
+    /// process, for the variables kept by [`Cmd::hermetic_env`] and to check
+    /// a relative [`Cmd::current_dir`], waits for any [`pushd`] or
+    /// [`pushenv`] guards held by other threads to be dropped. An unlocked
+    /// command doesn't wait, and might observe either the old or the new
+    /// state.
+    pub fn unlocked(mut self) -> Cmd {
+        self.unlocked = true;
+        self
+    }
+
+    /// Lets the command inherit the file descriptor `fd`, under the same
+    /// number.
+    ///
+    /// Normally, descriptors opened by Rust are closed on exec. This clears
+    /// the `FD_CLOEXEC` flag in the child, just before exec. The descriptor
+    /// must stay open until the command is spawned, and should not be one of
+    /// the standard streams, which are set up separately.
+    #[cfg(unix)]
+    pub fn keep_fd(mut self, fd: std::os::unix::io::RawFd) -> Cmd {
+        self.keep_fds.push(fd);
+        self
+    }
+
+    pub fn read(self) -> Result<String> {
+        self.with_retries(|cmd, _attempt| cmd.read_stdout())
+    }
+    /// Like [`Cmd::read`], but returns stdout as is, without decoding it or
+    /// stripping the final newline, for commands which output binary data.
+    ///
+    /// ```
+    /// # use xshell::cmd;
+    /// let bytes = cmd!("printf '\\377\n'").read_bytes()?;
+    /// assert_eq!(bytes, b"\xff\n");
+    /// # Ok::<(), xshell::Error>(())
+    /// ```
+    pub fn read_bytes(self) -> Result<Vec<u8>> {
+        self.with_retries(|cmd, _attempt| cmd.read_stdout_bytes())
+    }
+    /// Like [`Cmd::read`], but returns stderr instead of stdout.
+    ///
+    /// The stderr of a pipeline isn't captured, so this is an error for one.
+    pub fn read_stderr(self) -> Result<String> {
+        self.with_retries(|cmd, _attempt| {
+            cmd.check_not_redirected(false).map_err(CmdErrorKind::Io)?;
+            if !cmd.pipeline.is_empty() {
+                let msg = "can't read stderr of a pipeline";
+                return Err(CmdErrorKind::Io(io::Error::new(io::ErrorKind::Other, msg)));
+            }
+            decode_output(cmd.read_output()?.stderr, CmdErrorKind::NonUtf8Stderr)
+        })
+    }
+
+    /// Runs the command and checks that its output, minus the final newline,
+    /// is `expected`.
+    ///
+    /// On mismatch, the error shows a line-by-line diff. Handy for golden
+    /// tests of command-line tools.
+    ///
+    /// Requires the `extra-cmd` feature.
+    #[cfg(feature = "extra-cmd")]
+    pub fn read_expect(self, expected: &str) -> Result<()> {
+        let expected = expected.strip_suffix('\n').unwrap_or(expected);
+        self.with_retries(|cmd, _attempt| {
+            let actual = cmd.read_stdout()?;
+            if actual == expected {
+                return Ok(());
+            }
+            Err(CmdErrorKind::OutputMismatch { expected: expected.to_string(), actual })
+        })
+    }
+
+    /// Runs the command and parses its trimmed output.
+    ///
+    /// ```
+    /// # use xshell::cmd;
+    /// let n: u32 = cmd!("echo 92").read_parse()?;
+    /// assert_eq!(n, 92);
+    /// # Ok::<(), xshell::Error>(())
+    /// ```
+    ///
+    /// Requires the `extra-cmd` feature.
+    #[cfg(feature = "extra-cmd")]
+    pub fn read_parse<T>(self) -> Result<T>
+    where
+        T: std::str::FromStr,
+        T::Err: fmt::Display,
+    {
+        self.with_retries(|cmd, _attempt| {
+            let stdout = cmd.read_stdout()?;
+            stdout.trim().parse().map_err(|err: T::Err| {
+                let output = stdout.trim().to_string();
+                CmdErrorKind::Parse { output, message: err.to_string() }
+            })
+        })
+    }
+
+    fn read_stdout(&self) -> Result<String, CmdErrorKind> {
+        decode_output(self.read_stdout_bytes()?, CmdErrorKind::NonUtf8Stdout)
+    }
+    fn read_stdout_bytes(&self) -> Result<Vec<u8>, CmdErrorKind> {
+        self.check_not_redirected(true).map_err(CmdErrorKind::Io)?;
+        Ok(self.read_output()?.stdout)
+    }
+    /// Runs the command, or all stages of the pipeline, capturing the output
+    /// and checking the status. The stderr of a pipeline isn't captured.
+    fn read_output(&self) -> Result<Output, CmdErrorKind> {
+        self.check_status(self.read_raw().map(|output| (output.status, output)))
+    }
+    /// Runs the command and splits its stdout into records separated by
+    /// `delim`, like `\0` for the output of `find -print0`.
+    ///
+    /// The output isn't required to be UTF-8. A trailing empty record is
+    /// dropped.
+    ///
+    /// Requires the `extra-cmd` feature.
+    #[cfg(feature = "extra-cmd")]
+    pub fn read_split_by(self, delim: u8) -> Result<Vec<Vec<u8>>> {
+        self.with_retries(|cmd, _attempt| {
+            let stdout = cmd.read_stdout_bytes()?;
+            let mut res = stdout.split(|&b| b == delim).map(|it| it.to_vec()).collect::<Vec<_>>();
+            if res.last().map_or(false, |it| it.is_empty()) {
+                res.pop();
+            }
+            Ok(res)
+        })
+    }
+
+    /// Like [`Cmd::read_split_by`], but splits on a `char` and requires the
+    /// output to be UTF-8.
+    ///
+    /// Requires the `extra-cmd` feature.
+    #[cfg(feature = "extra-cmd")]
+    pub fn read_split_by_char(self, delim: char) -> Result<Vec<String>> {
+        self.with_retries(|cmd, _attempt| {
+            let stdout = cmd.read_stdout_bytes()?;
+            let stdout = String::from_utf8(stdout).map_err(CmdErrorKind::NonUtf8Stdout)?;
+            let mut res = stdout.split(delim).map(|it| it.to_string()).collect::<Vec<_>>();
+            if res.last().map_or(false, |it| it.is_empty()) {
+                res.pop();
+            }
+            Ok(res)
+        })
+    }
+
+    /// Runs the command and splits its stdout into lines.
+    ///
+    /// The final newline doesn't produce an empty line, and a `\r` before
+    /// each `\n` is dropped.
+    ///
+    /// ```
+    /// # use xshell::cmd;
+    /// let lines = cmd!("printf 'a\\nb\\n'").read_lines()?;
+    /// assert_eq!(lines, ["a", "b"]);
+    /// # Ok::<(), xshell::Error>(())
+    /// ```
+    pub fn read_lines(self) -> Result<Vec<String>> {
+        self.with_retries(|cmd, _attempt| {
+            Ok(cmd.read_stdout()?.lines().map(|it| it.to_string()).collect())
+        })
+    }
+
+    /// Like [`Cmd::read`], but drops the first `bytes` bytes of stdout before
+    /// decoding it.
+    ///
+    /// Useful for tools which prefix their output with a fixed-size binary
+    /// header, or with a byte order mark.
+    ///
+    /// Requires the `extra-cmd` feature.
+    #[cfg(feature = "extra-cmd")]
+    pub fn read_skip(self, bytes: usize) -> Result<String> {
+        self.with_retries(|cmd, _attempt| {
+            let mut stdout = cmd.read_stdout_bytes()?;
+            stdout.drain(..bytes.min(stdout.len()));
+            decode_output(stdout, CmdErrorKind::NonUtf8Stdout)
+        })
+    }
+    fn read_raw(&self) -> Result<Output, CmdErrorKind> {
+        #[cfg(feature = "mock")]
+        if let Some(output) = mock::output(self) {
+            return Ok(output);
+        }
+        let stderr = if self.pipeline.is_empty() { Stdio::piped() } else { Stdio::inherit() };
+        let mut output = (Vec::new(), Vec::new());
+        let status = self.exec(Stdio::null(), Stdio::piped(), stderr, &mut |stdout, stderr| {
+            output = read_pipes(stdout, stderr)?;
+            Ok(false)
+        })?;
+        let (stdout, stderr) = output;
+        Ok(Output { status, stdout, stderr })
+    }
+
+    /// Runs the command and returns its raw output, without decoding it.
+    ///
+    /// Like [`Cmd::read`], and unlike `Cmd::run_full`, a non-zero exit
+    /// status is an error, unless allowed with [`Cmd::ignore_status`] or
+    /// [`Cmd::ok_statuses`]. For a pipeline, the status is the one of the
+    /// last stage, and stderr is inherited rather than captured.
+    pub fn output(self) -> Result<Output> {
+        self.with_retries(|cmd, _attempt| cmd.read_output())
+    }
+
+    /// Like [`Cmd::read`], but returns stdout and stderr merged together, as
+    /// they would appear in a terminal.
+    ///
+    /// On Unix, both streams are connected to the same pipe, so the order of
+    /// the writes is preserved exactly. Elsewhere, they are read separately
+    /// and merged in the order the output arrives, which is best-effort.
+    ///
+    /// ```
+    /// # use xshell::cmd;
+    /// let out = cmd!("sh -c 'echo out; echo err >&2'").read_combined()?;
+    /// assert_eq!(out, "out\nerr");
+    /// # Ok::<(), xshell::Error>(())
+    /// ```
+    pub fn read_combined(self) -> Result<String> {
+        self.with_retries(|cmd, _attempt| {
+            let output = cmd.check_status(cmd.read_combined_raw())?;
+            decode_output(output, CmdErrorKind::NonUtf8Stdout)
+        })
+    }
+    fn read_combined_raw(&self) -> Result<(ExitStatus, Vec<u8>), CmdErrorKind> {
+        self.check_not_pipeline().map_err(CmdErrorKind::Io)?;
+        self.check_not_redirected(true).map_err(CmdErrorKind::Io)?;
+        self.check_not_redirected(false).map_err(CmdErrorKind::Io)?;
+        #[cfg(feature = "mock")]
+        if let Some(output) = mock::output(self) {
+            return Ok((output.status, [output.stdout, output.stderr].concat()));
+        }
+        let mut output = Vec::new();
+        #[cfg(unix)]
+        let status = {
+            let (mut reader, writer) = os_pipe().map_err(CmdErrorKind::Io)?;
+            let stdout = Stdio::from(writer.try_clone().map_err(CmdErrorKind::Io)?);
+            self.exec(Stdio::null(), stdout, Stdio::from(writer), &mut |_, _| {
+                reader.read_to_end(&mut output)?;
+                Ok(false)
+            })?
+        };
+        #[cfg(not(unix))]
+        let status = {
+            use std::sync::mpsc;
+
+            fn forward(
+                mut pipe: impl Read + Send + 'static,
+                tx: mpsc::Sender<io::Result<Vec<u8>>>,
+            ) -> Thread<()> {
+                spawn_thread(move || {
+                    let mut buf = [0; 8 * 1024];
+                    loop {
+                        let chunk = match pipe.read(&mut buf) {
+                            Ok(0) => return,
+                            Ok(n) => Ok(buf[..n].to_vec()),
+                            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                            Err(err) => Err(err),
+                        };
+                        let failed = chunk.is_err();
+                        if tx.send(chunk).is_err() || failed {
+                            return;
+                        }
+                    }
+                })
+            }
+
+            self.exec(Stdio::null(), Stdio::piped(), Stdio::piped(), &mut |stdout, stderr| {
+                let (tx, rx) = mpsc::channel();
+                let threads =
+                    vec![forward(stdout.unwrap(), tx.clone()), forward(stderr.unwrap(), tx)];
+                for chunk in rx {
+                    output.extend(chunk?);
                 }
-            } else if let Ok(child) = process::Command::new(cmd.next()?)
-                .args(&cmd.collect::<Vec<&&str>>())
-                .stdout(stdout)
-                .spawn()
-            {
-                Some(child)
-            } else {
-                None
+                for thread in threads {
+                    let _ = thread.join();
+                }
+                Ok(false)
+            })?
+        };
+        Ok((status, output))
+    }
+
+    /// Runs the command to completion, capturing everything about it.
+    ///
+    /// Unlike other methods, a non-zero exit status is not an error: only a
+    /// failure to spawn the command is. Output is decoded lossily.
+    ///
+    /// Requires the `extra-cmd` feature.
+    #[cfg(feature = "extra-cmd")]
+    pub fn run_full(self) -> Result<FullOutput> {
+        let start = Instant::now();
+        let output = self.check_not_pipeline().map_err(CmdErrorKind::Io);
+        match output.and_then(|()| self.read_raw()) {
+            Ok(output) => Ok(FullOutput {
+                stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+                stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+                status: output.status,
+                duration: start.elapsed(),
+            }),
+            Err(kind) => Err(kind.err(self)),
+        }
+    }
+
+    /// Runs the command, passing chunks of its stdout to `sink` as they
+    /// arrive, and returns the exit status.
+    ///
+    /// This is the building block for processing output incrementally. Stderr
+    /// is inherited. A non-zero exit status is not an error, but if `sink`
+    /// fails, the command is killed and the error is returned.
+    ///
+    /// Requires the `extra-cmd` feature.
+    #[cfg(feature = "extra-cmd")]
+    pub fn run_stdout(self, sink: impl FnMut(&[u8]) -> io::Result<()>) -> Result<ExitStatus> {
+        self.run_stdout_raw(sink).map_err(|kind| kind.err(self))
+    }
+    #[cfg(feature = "extra-cmd")]
+    fn run_stdout_raw(
+        &self,
+        mut sink: impl FnMut(&[u8]) -> io::Result<()>,
+    ) -> Result<ExitStatus, CmdErrorKind> {
+        #[cfg(feature = "mock")]
+        if let Some(output) = mock::output(self) {
+            io::stderr().write_all(&output.stderr).map_err(CmdErrorKind::Io)?;
+            if !output.stdout.is_empty() {
+                sink(&output.stdout).map_err(CmdErrorKind::Io)?;
+            }
+            return Ok(output.status);
+        }
+        self.exec(Stdio::null(), Stdio::piped(), Stdio::inherit(), &mut |stdout, _| {
+            let mut stdout = match stdout {
+                Some(it) => it,
+                None => return Ok(false),
+            };
+            let mut buf = [0; 8 * 1024];
+            loop {
+                match stdout.read(&mut buf) {
+                    Ok(0) => return Ok(false),
+                    Ok(n) => sink(&buf[..n])?,
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(err) => return Err(err),
+                }
+            }
+        })
+    }
+
+    /// Runs the command, passing lines of its stdout to `on_out` and lines of
+    /// its stderr to `on_err` as they arrive, and returns the exit status.
+    ///
+    /// Both streams are read concurrently, and neither is buffered in full.
+    /// Lines are decoded lossily and passed without the trailing newline. As
+    /// with [`Cmd::run_stdout`], a non-zero exit status is not an error.
+    ///
+    /// Requires the `extra-cmd` feature.
+    #[cfg(feature = "extra-cmd")]
+    pub fn run_split_streaming(
+        self,
+        on_out: impl FnMut(&str),
+        on_err: impl FnMut(&str),
+    ) -> Result<ExitStatus> {
+        self.run_split_streaming_raw(on_out, on_err).map_err(|kind| kind.err(self))
+    }
+    #[cfg(feature = "extra-cmd")]
+    fn run_split_streaming_raw(
+        &self,
+        mut on_out: impl FnMut(&str),
+        mut on_err: impl FnMut(&str),
+    ) -> Result<ExitStatus, CmdErrorKind> {
+        /// The lines read so far, in the order they arrive, and the number of
+        /// threads still reading.
+        type Lines = (Mutex<(VecDeque<io::Result<(bool, String)>>, usize)>, Condvar);
+
+        fn forward_lines(pipe: Pipe, lines: &Arc<Lines>) -> Thread<()> {
+            lines.0.lock().unwrap().1 += 1;
+            let lines = Arc::clone(lines);
+            spawn_thread(move || {
+                let (queue, cvar) = &*lines;
+                let is_err = matches!(pipe, Pipe::Stderr(_));
+                let mut stream = io::BufReader::new(pipe);
+                let mut buf = Vec::new();
+                loop {
+                    buf.clear();
+                    let msg = match io::BufRead::read_until(&mut stream, b'\n', &mut buf) {
+                        Ok(0) => break,
+                        Ok(_) => {
+                            if buf.ends_with(b"\n") {
+                                buf.pop();
+                            }
+                            Ok((is_err, String::from_utf8_lossy(&buf).into_owned()))
+                        }
+                        Err(err) => Err(err),
+                    };
+                    let failed = msg.is_err();
+                    queue.lock().unwrap().0.push_back(msg);
+                    cvar.notify_one();
+                    if failed {
+                        break;
+                    }
+                }
+                queue.lock().unwrap().1 -= 1;
+                cvar.notify_one();
+            })
+        }
+
+        self.check_not_pipeline().map_err(CmdErrorKind::Io)?;
+        #[cfg(feature = "mock")]
+        if let Some(output) = mock::output(self) {
+            mock::lines(&output.stdout).for_each(|line| on_out(&line));
+            mock::lines(&output.stderr).for_each(|line| on_err(&line));
+            return Ok(output.status);
+        }
+        self.exec(Stdio::null(), Stdio::piped(), Stdio::piped(), &mut |stdout, stderr| {
+            let lines = Arc::new((Mutex::new((VecDeque::new(), 0)), Condvar::new()));
+            // Streams redirected to a file are not captured.
+            let threads = vec![
+                stdout.map(|it| forward_lines(Pipe::Stdout(it), &lines)),
+                stderr.map(|it| forward_lines(Pipe::Stderr(it), &lines)),
+            ];
+            let (queue, cvar) = &*lines;
+            let mut res = Ok(false);
+            loop {
+                // The callbacks run without the lock, so that reading goes on.
+                let msg = {
+                    let mut queue = queue.lock().unwrap();
+                    loop {
+                        match queue.0.pop_front() {
+                            Some(msg) => break Some(msg),
+                            None if queue.1 == 0 => break None,
+                            None => queue = cvar.wait(queue).unwrap(),
+                        }
+                    }
+                };
+                match msg {
+                    Some(Ok((false, line))) => on_out(&line),
+                    Some(Ok((true, line))) => on_err(&line),
+                    Some(Err(err)) => res = Err(err),
+                    None => break,
+                }
+            }
+            for thread in threads.into_iter().flatten() {
+                let _ = thread.join();
             }
+            res
+        })
+    }
+
+    /// Runs the command and returns the last `n` lines of its stdout.
+    ///
+    /// Earlier lines are discarded as soon as they are read, so memory use is
+    /// bounded even for very verbose commands. Stderr is inherited.
+    ///
+    /// Requires the `extra-cmd` feature.
+    #[cfg(feature = "extra-cmd")]
+    pub fn read_tail(self, n: usize) -> Result<Vec<String>> {
+        self.with_retries(|cmd, _attempt| {
+            let lines = cmd.check_status(cmd.read_tail_raw(n))?;
+            let lines = lines.into_iter().map(String::from_utf8).collect::<Result<_, _>>();
+            lines.map_err(CmdErrorKind::NonUtf8Stdout)
+        })
+    }
+    #[cfg(feature = "extra-cmd")]
+    fn read_tail_raw(&self, n: usize) -> Result<(ExitStatus, VecDeque<Vec<u8>>), CmdErrorKind> {
+        fn tail(mut stdout: impl io::BufRead, n: usize) -> io::Result<VecDeque<Vec<u8>>> {
+            let mut lines = VecDeque::with_capacity(n);
+            loop {
+                let mut line = Vec::new();
+                if stdout.read_until(b'\n', &mut line)? == 0 {
+                    break;
+                }
+                if line.ends_with(b"\n") {
+                    line.pop();
+                }
+                if lines.len() == n {
+                    lines.pop_front();
+                }
+                if n > 0 {
+                    lines.push_back(line);
+                }
+            }
+            Ok(lines)
+        }
+
+        #[cfg(feature = "mock")]
+        if let Some(output) = mock::output(self) {
+            io::stderr().write_all(&output.stderr).map_err(CmdErrorKind::Io)?;
+            let lines = tail(&output.stdout[..], n).map_err(CmdErrorKind::Io)?;
+            return Ok((output.status, lines));
+        }
+        let mut lines = VecDeque::new();
+        let status =
+            self.exec(Stdio::null(), Stdio::piped(), Stdio::inherit(), &mut |stdout, _| {
+                if let Some(stdout) = stdout {
+                    lines = tail(io::BufReader::new(stdout), n)?;
+                }
+                Ok(false)
+            })?;
+        Ok((status, lines))
+    }
+
+    /// Runs the command and returns its stdout, decompressed.
+    ///
+    /// The output must be a gzip stream, as produced by `gzip -c`; multiple
+    /// concatenated members are decoded as one stream. Requires the `flate2`
+    /// feature.
+    #[cfg(feature = "flate2")]
+    pub fn read_gz(self) -> Result<Vec<u8>> {
+        self.with_retries(|cmd, _attempt| cmd.check_status(cmd.read_gz_raw()))
+    }
+    #[cfg(feature = "flate2")]
+    fn read_gz_raw(&self) -> Result<(ExitStatus, Vec<u8>), CmdErrorKind> {
+        fn decode(gz: impl Read, stdout: &mut Vec<u8>) -> io::Result<bool> {
+            flate2::read::MultiGzDecoder::new(gz).read_to_end(stdout).map(|_len| false)
+        }
+
+        let mut stdout = Vec::new();
+        #[cfg(feature = "mock")]
+        if let Some(output) = mock::output(self) {
+            io::stderr().write_all(&output.stderr).map_err(CmdErrorKind::Io)?;
+            decode(&output.stdout[..], &mut stdout).map_err(CmdErrorKind::Io)?;
+            return Ok((output.status, stdout));
+        }
+        let status = self.exec(
+            Stdio::null(),
+            Stdio::piped(),
+            Stdio::inherit(),
+            &mut |pipe, _| match pipe {
+                Some(pipe) => decode(pipe, &mut stdout),
+                None => Ok(false),
+            },
+        )?;
+        Ok((status, stdout))
+    }
+
+    /// Like [`Cmd::read`], but reads at most `max` bytes of output.
+    ///
+    /// If the command produces more, it is killed and the first `max` bytes
+    /// are returned, with the flag set to `true`. The output is decoded
+    /// lossily.
+    ///
+    /// Requires the `extra-cmd` feature.
+    #[cfg(feature = "extra-cmd")]
+    pub fn read_truncated(self, max: usize) -> Result<(String, bool)> {
+        self.with_retries(|cmd, _attempt| {
+            let (stdout, truncated, status) = cmd.read_truncated_raw(max)?;
+            if truncated {
+                return Ok((String::from_utf8_lossy(&stdout).into_owned(), true));
+            }
+            let mut stdout =
+                String::from_utf8_lossy(&cmd.check_status(Ok((status, stdout)))?).into_owned();
+            if stdout.ends_with('\n') {
+                stdout.pop();
+            }
+            Ok((stdout, false))
+        })
+    }
+    #[cfg(feature = "extra-cmd")]
+    fn read_truncated_raw(&self, max: usize) -> Result<(Vec<u8>, bool, ExitStatus), CmdErrorKind> {
+        #[cfg(feature = "mock")]
+        if let Some(mut output) = mock::output(self) {
+            io::stderr().write_all(&output.stderr).map_err(CmdErrorKind::Io)?;
+            let truncated = output.stdout.len() > max;
+            output.stdout.truncate(max);
+            return Ok((output.stdout, truncated, output.status));
+        }
+        let mut stdout = Vec::new();
+        let status =
+            self.exec(Stdio::null(), Stdio::piped(), Stdio::inherit(), &mut |pipe, _| {
+                if let Some(pipe) = pipe {
+                    pipe.take(max as u64 + 1).read_to_end(&mut stdout)?;
+                }
+                // Kill the command rather than waiting for it to finish.
+                Ok(stdout.len() > max)
+            })?;
+        let truncated = stdout.len() > max;
+        stdout.truncate(max);
+        Ok((stdout, truncated, status))
+    }
+
+    /// Spawns the command with the given stdout and stderr, feeding it the
+    /// contents set with [`Cmd::stdin`], if any, or `stdin` otherwise.
+    fn spawn_piped(&self, stdin: Stdio, stdout: Stdio, stderr: Stdio) -> io::Result<Child> {
+        let stdin = match &self.stdin_contents {
+            Some(StdinSource::Path(path)) => Stdio::from(open_redirect(path, File::open(path))?),
+            Some(_) => Stdio::piped(),
+            None => stdin,
         };
+        let mut child =
+            self.spawn_command(self.command().stdin(stdin).stderr(stderr).stdout(stdout))?;
 
-        let mut cmd = cmd.split(|c| c == &"|").peekable();
-        while let Some(c) = cmd.next() {
-            let stdout = if cmd.peek().is_some() {
-                process::Stdio::piped()
-            } else {
-                process::Stdio::inherit()
+        // Feed stdin from a separate thread: the child might fill up its
+        // output pipes before consuming all of the input.
+        if let Some(stdin_contents) = &self.stdin_contents {
+            let mut stdin = match child.stdin.take() {
+                Some(it) => it,
+                None => return Ok(child),
+            };
+            let stdin_contents = stdin_contents.clone();
+            spawn_thread(move || {
+                let _ = match stdin_contents {
+                    StdinSource::Bytes(bytes) => stdin.write_all(&bytes),
+                    StdinSource::Reader(reader) => match reader.lock().unwrap().take() {
+                        Some(mut reader) => io::copy(&mut reader, &mut stdin).map(drop),
+                        None => Ok(()),
+                    },
+                    StdinSource::Path(_) => unreachable!(),
+                };
+                let _ = stdin.flush();
+            });
+        }
+        Ok(child)
+    }
+
+    pub fn run(self) -> Result<()> {
+        self.with_retries(Cmd::run_once)
+    }
+    fn run_once(&self, attempt: u32) -> Result<(), CmdErrorKind> {
+        echo::echo(self, attempt);
+        #[cfg(feature = "mock")]
+        if let Some(status) = mock::status(self) {
+            return self.check_status(Ok((status, ())));
+        }
+        let res = match &self.log_prefix {
+            Some(prefix) if self.pipeline.is_empty() => self.run_prefixed(prefix),
+            _ => self
+                .exec(Stdio::inherit(), Stdio::inherit(), Stdio::inherit(), &mut |_, _| Ok(false)),
+        };
+        self.check_status(res.map(|status| (status, ())))
+    }
+    /// Runs the command, printing each line of its output after `prefix`.
+    ///
+    /// The lines are printed by the threads reading them, so, unlike for
+    /// `run_split_streaming`, there's no need for a queue.
+    fn run_prefixed(&self, prefix: &str) -> Result<ExitStatus, CmdErrorKind> {
+        fn print_lines(mut pipe: Pipe, prefix: &str) -> io::Result<()> {
+            let is_err = matches!(pipe, Pipe::Stderr(_));
+            let print = |line: &[u8]| {
+                let line = String::from_utf8_lossy(line);
+                if is_err {
+                    eprintln!("{}{}", prefix, line);
+                } else {
+                    println!("{}{}", prefix, line);
+                }
             };
-            stdin = runit(stdin, stdout, c);
+            let mut buf = Vec::new();
+            let mut chunk = [0; 4096];
+            loop {
+                let n = match pipe.read(&mut chunk) {
+                    Ok(0) => break,
+                    Ok(n) => n,
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(err) => return Err(err),
+                };
+                buf.extend_from_slice(&chunk[..n]);
+                let mut start = 0;
+                while let Some(len) = buf[start..].iter().position(|&b| b == b'\n') {
+                    print(&buf[start..start + len]);
+                    start += len + 1;
+                }
+                buf = buf.split_off(start);
+            }
+            if !buf.is_empty() {
+                print(&buf);
+            }
+            Ok(())
+        }
+
+        self.exec(Stdio::null(), Stdio::piped(), Stdio::piped(), &mut |stdout, stderr| {
+            let stderr = stderr.map(|pipe| {
+                let prefix = prefix.to_string();
+                spawn_thread(move || print_lines(Pipe::Stderr(pipe), &prefix))
+            });
+            let res = stdout.map_or(Ok(()), |pipe| print_lines(Pipe::Stdout(pipe), prefix));
+            let stderr_res = stderr.map_or(Ok(()), |thread| thread.join().unwrap());
+            res.and(stderr_res).map(|()| false)
+        })
+    }
+    /// Calls `f` with the attempt number, starting at 1, until it succeeds or
+    /// the attempts set with [`Cmd::retry`] run out.
+    fn with_retries<T>(self, f: impl Fn(&Cmd, u32) -> Result<T, CmdErrorKind>) -> Result<T> {
+        let mut res = None;
+        self.retry_loop(&mut |cmd, attempt| {
+            res = Some(f(cmd, attempt)?);
+            Ok(())
+        })?;
+        Ok(res.unwrap())
+    }
+    // Not generic, so that it's compiled once rather than for every runner.
+    fn retry_loop(self, f: &mut dyn FnMut(&Cmd, u32) -> Result<(), CmdErrorKind>) -> Result<()> {
+        let (attempts, delay) = self.retry.unwrap_or((1, Duration::from_secs(0)));
+        let mut attempt = 1;
+        loop {
+            match f(&self, attempt) {
+                Ok(()) => return Ok(()),
+                Err(_) if attempt < attempts => {
+                    thread::sleep(delay);
+                    attempt += 1;
+                }
+                Err(kind) => return Err(kind.err(self)),
+            }
         }
-        // wait for the last command
-        if let Some(process) = stdin.as_mut() {
-            let _ = process.wait();
-            let mut out = Vec::new();
-            process.stdout.as_mut().unwrap().read_exact(&mut out).unwrap();
+    }
 
-            return Ok(String::from_utf8_lossy(&out).to_string());
+    /// Runs an interactive program, like an editor or `ssh`, connecting
+    /// stdin, stdout and stderr to those of the current process, so that the
+    /// program sees the terminal.
+    ///
+    /// Unlike [`Cmd::run`], stdin is always inherited, and it is an error to
+    /// set it with [`Cmd::stdin`] or to run a pipeline. The command is echoed
+    /// as configured with [`Cmd::echo_to`].
+    ///
+    /// Requires the `extra-cmd` feature.
+    #[cfg(feature = "extra-cmd")]
+    pub fn run_interactive(self) -> Result<()> {
+        self.with_retries(|cmd, attempt| {
+            echo::echo(cmd, attempt);
+            #[cfg(feature = "mock")]
+            if let Some(status) = mock::status(cmd) {
+                return cmd.check_status(Ok((status, ())));
+            }
+            cmd.check_status(cmd.run_interactive_raw().map(|status| (status, ())))
+        })
+    }
+    #[cfg(feature = "extra-cmd")]
+    fn run_interactive_raw(&self) -> Result<ExitStatus, CmdErrorKind> {
+        let msg = if self.stdin_contents.is_some() {
+            Some("stdin is set, can't connect it to the terminal")
+        } else if !self.pipeline.is_empty() {
+            Some("can't run a pipeline interactively")
         } else {
-            Ok(String::new())
+            None
+        };
+        if let Some(msg) = msg {
+            return Err(CmdErrorKind::Io(io::Error::new(io::ErrorKind::Other, msg)));
         }
+        self.exec(Stdio::inherit(), Stdio::inherit(), Stdio::inherit(), &mut |_, _| Ok(false))
     }
+
+    /// Like [`Cmd::run`], but converts the error with `f`, for libraries with
+    /// their own error type.
+    ///
+    /// Requires the `extra-cmd` feature.
+    #[cfg(feature = "extra-cmd")]
+    pub fn run_map_err<E>(self, f: impl FnOnce(Error) -> E) -> Result<(), E> {
+        self.run().map_err(f)
+    }
+
+    /// Like [`Cmd::run`], but prints a line with the elapsed time to stderr
+    /// every `interval`, to show that a long command isn't stuck.
+    ///
+    /// The line is updated in place and cleared once the command finishes.
+    ///
+    /// Requires the `extra-cmd` feature.
+    #[cfg(feature = "extra-cmd")]
+    pub fn run_with_heartbeat(self, interval: Duration) -> Result<()> {
+        self.with_retries(|cmd, attempt| cmd.run_with_heartbeat_once(interval, attempt))
+    }
+    #[cfg(feature = "extra-cmd")]
+    fn run_with_heartbeat_once(
+        &self,
+        interval: Duration,
+        attempt: u32,
+    ) -> Result<(), CmdErrorKind> {
+        echo::echo(self, attempt);
+        #[cfg(feature = "mock")]
+        if let Some(status) = mock::status(self) {
+            return self.check_status(Ok((status, ())));
+        }
+        let done = Arc::new(AtomicBool::new(false));
+        let heartbeat = spawn_thread({
+            let done = Arc::clone(&done);
+            move || {
+                let start = Instant::now();
+                let mut next = start + interval;
+                let mut line_len = 0;
+                // Parking may wake up early, so check both the flag and the time.
+                while !done.load(Ordering::SeqCst) {
+                    let now = Instant::now();
+                    if now < next {
+                        thread::park_timeout(next - now);
+                        continue;
+                    }
+                    let line = format!("still running ({:.0?})", start.elapsed());
+                    eprint!("\r{}", line);
+                    line_len = line.len();
+                    next += interval;
+                }
+                if line_len > 0 {
+                    eprint!("\r{}\r", " ".repeat(line_len));
+                }
+            }
+        });
+        let res =
+            self.exec(Stdio::inherit(), Stdio::inherit(), Stdio::inherit(), &mut |_, _| Ok(false));
+        done.store(true, Ordering::SeqCst);
+        heartbeat.handle.thread().unpark();
+        let _ = heartbeat.join();
+        self.check_status(res.map(|status| (status, ())))
+    }
+
+    /// Like [`Cmd::run`], but also keeps the last few kilobytes of output in
+    /// memory, and attaches them to the error if the command fails.
+    ///
+    /// Output is still printed as it arrives. Useful for chatty commands whose
+    /// output matters only on failure, like CI steps.
+    ///
+    /// Requires the `extra-cmd` feature.
+    #[cfg(feature = "extra-cmd")]
+    pub fn run_capture_on_error(self) -> Result<()> {
+        self.with_retries(|cmd, attempt| {
+            echo::echo(cmd, attempt);
+            match cmd.run_capture_on_error_raw()? {
+                (status, _tail) if cmd.status_ok(&status) => Ok(()),
+                (status, tail) => Err(CmdErrorKind::NonZeroStatusWithOutput { status, tail }),
+            }
+        })
+    }
+    #[cfg(feature = "extra-cmd")]
+    fn run_capture_on_error_raw(&self) -> Result<(ExitStatus, String), CmdErrorKind> {
+        const TAIL_LEN: usize = 4 * 1024;
+
+        fn tee(
+            mut src: impl Read,
+            mut dst: impl Write,
+            tail: &Mutex<VecDeque<u8>>,
+        ) -> io::Result<()> {
+            let mut buf = [0; 8 * 1024];
+            loop {
+                let n = match src.read(&mut buf) {
+                    Ok(0) => return Ok(()),
+                    Ok(n) => n,
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(err) => return Err(err),
+                };
+                dst.write_all(&buf[..n])?;
+                dst.flush()?;
+                let mut tail = tail.lock().unwrap();
+                tail.extend(&buf[..n]);
+                let excess = tail.len().saturating_sub(TAIL_LEN);
+                tail.drain(..excess);
+            }
+        }
+
+        self.check_not_pipeline().map_err(CmdErrorKind::Io)?;
+        let tail = Arc::new(Mutex::new(VecDeque::with_capacity(TAIL_LEN)));
+        #[cfg(feature = "mock")]
+        if let Some(output) = mock::output(self) {
+            tee(&output.stdout[..], io::stdout(), &tail).map_err(CmdErrorKind::Io)?;
+            tee(&output.stderr[..], io::stderr(), &tail).map_err(CmdErrorKind::Io)?;
+            let tail = tail.lock().unwrap().iter().copied().collect::<Vec<u8>>();
+            return Ok((output.status, String::from_utf8_lossy(&tail).into_owned()));
+        }
+        let status =
+            self.exec(Stdio::null(), Stdio::piped(), Stdio::piped(), &mut |stdout, stderr| {
+                let stderr = stderr.map(|stderr| {
+                    let tail = Arc::clone(&tail);
+                    spawn_thread(move || tee(stderr, io::stderr(), &tail))
+                });
+                let stdout_res = stdout.map_or(Ok(()), |stdout| tee(stdout, io::stdout(), &tail));
+                let stderr_res = stderr.map_or(Ok(()), |thread| thread.join().unwrap());
+                stdout_res.and(stderr_res).map(|()| false)
+            })?;
+        let tail = tail.lock().unwrap().iter().copied().collect::<Vec<u8>>();
+        Ok((status, String::from_utf8_lossy(&tail).into_owned()))
+    }
+
+    /// Like [`Cmd::run`], but also reports the resources used by the command.
+    ///
+    /// Requires the `libc` feature. Not supported on Windows.
+    #[cfg(all(unix, feature = "libc"))]
+    pub fn run_rusage(self) -> Result<ResourceUsage> {
+        self.with_retries(Cmd::run_rusage_once)
+    }
+    #[cfg(all(unix, feature = "libc"))]
+    fn run_rusage_once(&self, attempt: u32) -> Result<ResourceUsage, CmdErrorKind> {
+        echo::echo(self, attempt);
+        #[cfg(feature = "mock")]
+        if let Some(status) = mock::status(self) {
+            let zero = Duration::from_secs(0);
+            let usage = ResourceUsage { max_rss: 0, user_time: zero, sys_time: zero };
+            return self.check_status(Ok((status, usage)));
+        }
+        let res = if self.pipeline.is_empty() {
+            self.spawn_command(&mut self.command()).and_then(|child| wait4(child, self.timeout))
+        } else {
+            let msg = "can't report the resource usage of a pipeline";
+            Err(io::Error::new(io::ErrorKind::Other, msg))
+        };
+        self.check_status(res.map_err(CmdErrorKind::Io))
+    }
+
+    /// Starts the command in the background, inheriting stdout and stderr.
+    ///
+    /// Unlike [`Cmd::run`], the command is not echoed. Only the arguments,
+    /// environment, working directory and redirects apply: the child
+    /// inherits stdin, so [`Cmd::stdin`] and friends are ignored, as are
+    /// [`Cmd::timeout`], [`Cmd::retry`], pipelines and mocks.
+    pub fn spawn(self) -> Result<Handle> {
+        match self.spawn_command(&mut self.command()) {
+            Ok(child) => Ok(Handle { child, cmd: self }),
+            Err(io_err) => Err(CmdErrorKind::Io(io_err).err(self)),
+        }
+    }
+
+    /// Spawns `command`, without the global shell lock: `Command::spawn`
+    /// reads the working directory and environment on its own, and holding
+    /// the lock would deadlock with a thread which waits for this one inside
+    /// a [`pushd`].
+    fn spawn_command(&self, command: &mut std::process::Command) -> io::Result<Child> {
+        if self.args[0].is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, EmptyProgram));
+        }
+        if let Some(dir) = &self.current_dir {
+            let _guard = if dir.is_relative() { self.lock() } else { None };
+            // Otherwise, this is reported as the program not being found.
+            if !dir.is_dir() {
+                let msg = "working directory doesn't exist";
+                return Err(io::Error::new(io::ErrorKind::Other, msg));
+            }
+        }
+        // For a pipeline, stdout is redirected by the last stage.
+        if let Some(redirect) = self.stdout_redirect.as_ref().filter(|_| self.pipeline.is_empty()) {
+            command.stdout(redirect.open()?);
+        }
+        if let Some(redirect) = &self.stderr_redirect {
+            command.stderr(redirect.open()?);
+        }
+        #[cfg(feature = "ctrlc")]
+        let res = interrupt::spawn(|| command.spawn());
+        #[cfg(not(feature = "ctrlc"))]
+        let res = command.spawn();
+        res
+    }
+
+    /// Takes the global shell lock in shared mode, unless [`Cmd::unlocked`]
+    /// is set, while the command reads the state of the process.
+    fn lock(&self) -> Option<gsl::Guard> {
+        if self.unlocked {
+            None
+        } else {
+            Some(gsl::read())
+        }
+    }
+
+    fn command(&self) -> std::process::Command {
+        let mut res = std::process::Command::new(&self.args[0]);
+        res.args(&self.args[1..]);
+        if let Some(dir) = &self.current_dir {
+            res.current_dir(dir);
+        }
+        if self.env_clear {
+            res.env_clear();
+            let _guard = self.lock();
+            for key in &self.env_keep {
+                if let Some(value) = std::env::var_os(key) {
+                    res.env(key, value);
+                }
+            }
+        }
+        for (key, value) in &self.env_changes {
+            match value {
+                Some(value) => res.env(key, value),
+                None => res.env_remove(key),
+            };
+        }
+        #[cfg(unix)]
+        if !self.keep_fds.is_empty() {
+            use std::os::unix::process::CommandExt;
+            let fds = self.keep_fds.clone();
+            // SAFETY: `set_cloexec` only calls `fcntl`, which is
+            // async-signal-safe.
+            unsafe {
+                res.pre_exec(move || fds.iter().try_for_each(|&fd| set_cloexec(fd, false)));
+            }
+        }
+        res
+    }
+}
+
+/// Where [`Cmd::run`] and friends echo the command, see [`Cmd::echo_to`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EchoTarget {
+    Stdout,
+    Stderr,
+    None,
+}
+
+/// Everything about a finished command, returned by [`Cmd::run_full`].
+///
+/// Requires the `extra-cmd` feature.
+#[cfg(feature = "extra-cmd")]
+#[non_exhaustive]
+#[derive(Debug, Clone)]
+pub struct FullOutput {
+    pub stdout: String,
+    pub stderr: String,
+    pub status: ExitStatus,
+    /// Wall-clock time from spawning the command to its exit.
+    pub duration: Duration,
+}
+
+/// A running command, returned by [`Cmd::spawn`].
+#[must_use]
+#[derive(Debug)]
+pub struct Handle {
+    child: Child,
+    cmd: Cmd,
+}
+
+impl Handle {
+    /// OS-assigned process identifier of the child.
+    pub fn id(&self) -> u32 {
+        self.child.id()
+    }
+
+    /// Waits for the command to finish, erroring on non-zero exit status.
+    pub fn wait(mut self) -> Result<()> {
+        let res = self.child.wait().map(|status| (status, ())).map_err(CmdErrorKind::Io);
+        self.cmd.check_status(res).map_err(|kind| kind.err(self.cmd))
+    }
+
+    /// Kills the command right away.
+    ///
+    /// The command still needs to be waited for: [`Handle::wait`] then
+    /// reports that it was killed.
+    pub fn kill(&mut self) -> Result<()> {
+        match self.child.kill() {
+            Ok(()) => Ok(()),
+            Err(io_err) => Err(CmdErrorKind::Io(io_err).err(self.cmd.clone())),
+        }
+    }
+
+    /// Asks the command to exit, giving it `grace` to do so before killing
+    /// it, and returns the final status.
+    ///
+    /// On Unix, this sends `SIGTERM` first. On Windows, where there's no
+    /// equivalent signal, the command is killed once `grace` elapses.
+    pub fn shutdown(mut self, grace: Duration) -> Result<ExitStatus> {
+        match self._shutdown(grace) {
+            Ok(status) => Ok(status),
+            Err(io_err) => Err(CmdErrorKind::Io(io_err).err(self.cmd)),
+        }
+    }
+    fn _shutdown(&mut self, grace: Duration) -> io::Result<ExitStatus> {
+        if let Some(status) = self.child.try_wait()? {
+            return Ok(status);
+        }
+        terminate(&self.child)?;
+        let deadline = Instant::now() + grace;
+        loop {
+            if let Some(status) = self.child.try_wait()? {
+                return Ok(status);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            thread::sleep((deadline - now).min(Duration::from_millis(10)));
+        }
+        self.child.kill()?;
+        self.child.wait()
+    }
+}
+
+/// Resources used by a finished command, returned by [`Cmd::run_rusage`].
+#[cfg(all(unix, feature = "libc"))]
+#[derive(Debug, Clone, Copy)]
+pub struct ResourceUsage {
+    /// Peak resident set size, in bytes.
+    pub max_rss: u64,
+    /// Time spent executing in user mode.
+    pub user_time: Duration,
+    /// Time spent executing in kernel mode.
+    pub sys_time: Duration,
+}
+
+#[cfg(all(unix, feature = "libc"))]
+fn wait4(mut child: Child, timeout: Option<Duration>) -> io::Result<(ExitStatus, ResourceUsage)> {
+    use std::os::unix::process::ExitStatusExt;
+
+    let start = Instant::now();
+    // With a timeout, poll the child, like `Child::try_wait` does.
+    let options = if timeout.is_some() { libc::WNOHANG } else { 0 };
+    let mut status = 0;
+    let mut rusage: libc::rusage = unsafe { std::mem::zeroed() };
+    loop {
+        let pid = child.id() as libc::pid_t;
+        match unsafe { libc::wait4(pid, &mut status, options, &mut rusage) } {
+            -1 => {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::Interrupted {
+                    return Err(err);
+                }
+            }
+            0 => {
+                let (elapsed, timeout) = (start.elapsed(), timeout.unwrap());
+                if elapsed >= timeout {
+                    child.kill()?;
+                    child.wait()?;
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, Timeout(elapsed)));
+                }
+                thread::sleep((timeout - elapsed).min(Duration::from_millis(10)));
+            }
+            _ => break,
+        }
+    }
+
+    let timeval = |tv: libc::timeval| {
+        Duration::from_secs(tv.tv_sec as u64) + Duration::from_micros(tv.tv_usec as u64)
+    };
+    // Linux and BSDs report kilobytes, macOS reports bytes.
+    let rss_unit = if cfg!(target_os = "macos") { 1 } else { 1024 };
+    let usage = ResourceUsage {
+        max_rss: rusage.ru_maxrss as u64 * rss_unit,
+        user_time: timeval(rusage.ru_utime),
+        sys_time: timeval(rusage.ru_stime),
+    };
+    Ok((ExitStatus::from_raw(status), usage))
+}
+
+#[cfg(unix)]
+fn terminate(child: &Child) -> io::Result<()> {
+    extern "C" {
+        fn kill(pid: i32, sig: i32) -> i32;
+    }
+    const SIGTERM: i32 = 15;
+    if unsafe { kill(child.id() as i32, SIGTERM) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Adds the path to an error opening a file for redirection.
+fn open_redirect(path: &Path, res: io::Result<File>) -> io::Result<File> {
+    res.map_err(|io_err| {
+        let mut msg = io_err.to_string();
+        if msg.is_char_boundary(1) {
+            msg[..1].make_ascii_lowercase();
+        }
+        io::Error::new(io_err.kind(), format!("`{}`: {}", path.display(), msg))
+    })
+}
+
+#[cfg(unix)]
+fn set_cloexec(fd: std::os::unix::io::RawFd, cloexec: bool) -> io::Result<()> {
+    extern "C" {
+        fn fcntl(fd: i32, cmd: i32, ...) -> i32;
+    }
+    const F_GETFD: i32 = 1;
+    const F_SETFD: i32 = 2;
+    const FD_CLOEXEC: i32 = 1;
+    unsafe {
+        let flags = fcntl(fd, F_GETFD);
+        let new_flags = if cloexec { flags | FD_CLOEXEC } else { flags & !FD_CLOEXEC };
+        if flags == -1 || fcntl(fd, F_SETFD, new_flags) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Returns the read and write ends of a new pipe.
+///
+/// Both ends are close-on-exec. Otherwise, commands spawned concurrently
+/// inherit the write end, and the pipe isn't closed until they exit.
+#[cfg(all(
+    feature = "libc",
+    any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "dragonfly",
+        target_os = "netbsd",
+        target_os = "openbsd"
+    )
+))]
+fn os_pipe() -> io::Result<(File, File)> {
+    use std::os::unix::io::FromRawFd;
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the descriptors were just created, nothing else owns them.
+    Ok(unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) })
+}
+
+/// Like the `pipe2` version, but the flag is set after the fact, so a
+/// command spawned by another thread in between might still inherit the
+/// pipe. Used without the `libc` feature, and where `pipe2` doesn't exist.
+#[cfg(all(
+    unix,
+    not(all(
+        feature = "libc",
+        any(
+            target_os = "linux",
+            target_os = "android",
+            target_os = "freebsd",
+            target_os = "dragonfly",
+            target_os = "netbsd",
+            target_os = "openbsd"
+        )
+    ))
+))]
+fn os_pipe() -> io::Result<(File, File)> {
+    use std::os::unix::io::FromRawFd;
+    extern "C" {
+        fn pipe(fds: *mut i32) -> i32;
+    }
+    let mut fds = [0; 2];
+    if unsafe { pipe(fds.as_mut_ptr()) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the descriptors were just created, nothing else owns them.
+    let res = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+    set_cloexec(fds[0], true)?;
+    set_cloexec(fds[1], true)?;
+    Ok(res)
+}
+
+/// Either pipe of a child, so that the code reading them is compiled only
+/// once.
+enum Pipe {
+    Stdout(ChildStdout),
+    Stderr(ChildStderr),
+}
+
+impl Read for Pipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Pipe::Stdout(it) => it.read(buf),
+            Pipe::Stderr(it) => it.read(buf),
+        }
+    }
+}
+
+/// A thread spawned with [`spawn_thread`].
+struct Thread<T> {
+    handle: thread::JoinHandle<Box<dyn Any + Send>>,
+    _output: PhantomData<T>,
+}
+
+impl<T: 'static> Thread<T> {
+    fn join(self) -> thread::Result<T> {
+        self.handle.join().map(|it| *it.downcast::<T>().unwrap())
+    }
+}
+
+/// Like [`thread::spawn`], but boxes `f`, as spawning instantiates a lot of
+/// code for every closure type.
+fn spawn_thread<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> Thread<T> {
+    fn spawn_boxed(
+        f: Box<dyn FnOnce() -> Box<dyn Any + Send> + Send>,
+    ) -> thread::JoinHandle<Box<dyn Any + Send>> {
+        thread::spawn(f)
+    }
+    let handle = spawn_boxed(Box::new(move || Box::new(f()) as Box<dyn Any + Send>));
+    Thread { handle, _output: PhantomData }
+}
+
+/// Reads both pipes to the end, stderr on another thread, so that the child
+/// doesn't block on a full pipe.
+fn read_pipes(
+    stdout: Option<ChildStdout>,
+    stderr: Option<ChildStderr>,
+) -> io::Result<(Vec<u8>, Vec<u8>)> {
+    let stderr = stderr.map(|mut pipe| {
+        spawn_thread(move || {
+            let mut buf = Vec::new();
+            pipe.read_to_end(&mut buf).map(|_len| buf)
+        })
+    });
+    let mut out = Vec::new();
+    if let Some(mut pipe) = stdout {
+        pipe.read_to_end(&mut out)?;
+    }
+    let err = match stderr {
+        Some(thread) => thread.join().unwrap()?,
+        None => Vec::new(),
+    };
+    Ok((out, err))
+}
+
+/// Waits for `children` in order, killing the ones still running once
+/// `timeout` expires, or once `kill` is set.
+fn wait_all(
+    mut children: Vec<Child>,
+    timeout: Option<Duration>,
+    kill: &AtomicBool,
+) -> io::Result<Vec<ExitStatus>> {
+    let start = Instant::now();
+    let mut statuses = Vec::new();
+    let mut killed = false;
+    for i in 0..children.len() {
+        let timeout = match timeout {
+            Some(it) if !killed => it,
+            _ => {
+                if !killed && kill.load(Ordering::SeqCst) {
+                    children[i..].iter_mut().for_each(|child| drop(child.kill()));
+                    killed = true;
+                }
+                statuses.push(children[i].wait()?);
+                continue;
+            }
+        };
+        let status = loop {
+            if let Some(status) = children[i].try_wait()? {
+                break status;
+            }
+            let elapsed = start.elapsed();
+            if elapsed >= timeout || kill.load(Ordering::SeqCst) {
+                for child in &mut children[i..] {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                }
+                if elapsed >= timeout {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, Timeout(elapsed)));
+                }
+                killed = true;
+                break children[i].wait()?;
+            }
+            thread::sleep((timeout - elapsed).min(Duration::from_millis(10)));
+        };
+        statuses.push(status);
+    }
+    Ok(statuses)
+}
+
+/// Decodes captured output, dropping the final newline.
+fn decode_output(
+    output: Vec<u8>,
+    non_utf8: fn(std::string::FromUtf8Error) -> CmdErrorKind,
+) -> Result<String, CmdErrorKind> {
+    let mut output = String::from_utf8(output).map_err(non_utf8)?;
+    if output.ends_with('\n') {
+        output.pop();
+    }
+    Ok(output)
+}
+
+#[cfg(unix)]
+fn is_sigpipe(status: &ExitStatus) -> bool {
+    use std::os::unix::process::ExitStatusExt;
+    const SIGPIPE: i32 = 13;
+    status.signal() == Some(SIGPIPE)
+}
+
+#[cfg(not(unix))]
+fn is_sigpipe(_status: &ExitStatus) -> bool {
+    false
+}
+
+#[cfg(not(unix))]
+fn terminate(_child: &Child) -> io::Result<()> {
+    Ok(())
 }
@@ -0,0 +1,124 @@
+//! Faking command execution in tests
+use std::{
+    io::{self, Write},
+    process::{ExitStatus, Output},
+    sync::{Arc, Mutex},
+    thread::{self, ThreadId},
+};
+
+use crate::{gsl, Cmd};
+
+type Handler = Arc<dyn Fn(&Cmd) -> Option<MockOutput> + Send + Sync>;
+
+/// Canned result of a mocked command, see [`set_mock`].
+#[derive(Debug, Clone, Default)]
+pub struct MockOutput {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    pub exit_code: i32,
+}
+
+/// Intercepts the commands run by the current thread until the returned
+/// guard is dropped.
+///
+/// `handler` is called with each command instead of spawning it. If it
+/// returns `None`, the command is spawned for real. Requires the `mock`
+/// feature.
+///
+/// Every method which runs the command to completion is intercepted, from
+/// [`Cmd::run`](crate::Cmd::run) and [`Cmd::read`](crate::Cmd::read) to
+/// the streaming ones like `Cmd::run_stdout`.
+/// Where a real command would inherit a stream, the canned output is
+/// printed instead. A pipeline is passed to `handler` as a whole, as the
+/// first stage. Only [`Cmd::spawn`](crate::Cmd::spawn) is never mocked.
+///
+/// The guard holds the global lock, like [`pushd`](crate::pushd) does, so
+/// only one thread at a time can install a mock. Commands run by other
+/// threads are neither mocked nor blocked by it.
+///
+/// ```
+/// use xshell::{cmd, set_mock, MockOutput};
+///
+/// let _mock = set_mock(|cmd| {
+///     if cmd.get_program() != "git" {
+///         return None;
+///     }
+///     Some(MockOutput { stdout: b"main\n".to_vec(), ..MockOutput::default() })
+/// });
+/// assert_eq!(cmd!("git branch --show-current").read()?, "main");
+/// # Ok::<(), xshell::Error>(())
+/// ```
+pub fn set_mock(handler: impl Fn(&Cmd) -> Option<MockOutput> + Send + Sync + 'static) -> MockGuard {
+    let guard = gsl::write();
+    let handler: Handler = Arc::new(handler);
+    *handler_slot().lock().unwrap_or_else(|err| err.into_inner()) =
+        Some((thread::current().id(), handler));
+    MockGuard { _guard: guard }
+}
+
+/// Removes the mock installed by [`set_mock`] on drop.
+#[must_use]
+pub struct MockGuard {
+    _guard: gsl::Guard,
+}
+
+impl Drop for MockGuard {
+    fn drop(&mut self) {
+        *handler_slot().lock().unwrap_or_else(|err| err.into_inner()) = None;
+    }
+}
+
+/// Returns the mocked output of `cmd`, if any.
+pub(crate) fn output(cmd: &Cmd) -> Option<Output> {
+    // Cloned, so that the handler can run commands itself.
+    let (thread, handler) = handler_slot().lock().unwrap_or_else(|err| err.into_inner()).clone()?;
+    if thread != thread::current().id() {
+        return None;
+    }
+    let output = handler(cmd)?;
+    Some(Output {
+        status: exit_status(output.exit_code),
+        stdout: output.stdout,
+        stderr: output.stderr,
+    })
+}
+
+/// Like [`output`], but prints the canned output as an inheriting child would.
+pub(crate) fn status(cmd: &Cmd) -> Option<ExitStatus> {
+    let output = output(cmd)?;
+    match &cmd.log_prefix {
+        Some(prefix) => {
+            lines(&output.stdout).for_each(|line| println!("{}{}", prefix, line));
+            lines(&output.stderr).for_each(|line| eprintln!("{}{}", prefix, line));
+        }
+        None => {
+            let _ = io::stdout().write_all(&output.stdout);
+            let _ = io::stderr().write_all(&output.stderr);
+        }
+    }
+    Some(output.status)
+}
+
+/// Splits canned output into lines, the way a child's output is read line by
+/// line.
+pub(crate) fn lines(output: &[u8]) -> impl Iterator<Item = std::borrow::Cow<'_, str>> {
+    let output = output.strip_suffix(b"\n").unwrap_or(output);
+    let lines = if output.is_empty() { None } else { Some(output.split(|&b| b == b'\n')) };
+    lines.into_iter().flatten().map(String::from_utf8_lossy)
+}
+
+#[cfg(unix)]
+fn exit_status(code: i32) -> ExitStatus {
+    use std::os::unix::process::ExitStatusExt;
+    ExitStatus::from_raw((code & 0xff) << 8)
+}
+
+#[cfg(windows)]
+fn exit_status(code: i32) -> ExitStatus {
+    use std::os::windows::process::ExitStatusExt;
+    ExitStatus::from_raw(code as u32)
+}
+
+fn handler_slot() -> &'static Mutex<Option<(ThreadId, Handler)>> {
+    global!(Mutex<Option<(ThreadId, Handler)>>, Mutex::new(None))
+}
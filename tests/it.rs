@@ -1,7 +1,24 @@
+use std::{
+    ffi::OsStr,
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+    time::Instant,
+};
 
-use std::{ffi::OsStr, thread, time::Duration, time::Instant};
-
-use xshell::{cmd, cwd, pushd, pushenv, read_file, rm_rf};
+use xshell::{
+    cd, cmd, cp_r, cwd, default_echo_hook, mkdir_p, mktemp_f, mv, normalize, num_cpus, pushd,
+    pushenv, pushenv_prev, pushenv_remove, read_dir, read_file, read_file_bytes, rm_rf,
+    rm_rf_strict, set_echo_hook, target_bin, temp_dir, touch, with_temp_dir, write_file, Cmd,
+    EchoTarget,
+};
+#[cfg(feature = "extra-fs")]
+use xshell::{
+    cp_if_changed, cp_if_newer, glob, hard_link, join_path_dirs, path_dirs, pushd_glob,
+    read_dir_entries, walk_dir, walk_dir_iter,
+};
+#[cfg(feature = "extra-cmd")]
+use xshell::{run_in_dirs, Batch};
 
 #[test]
 fn smoke() {
@@ -60,28 +77,485 @@ fn interpolation_spat() {
     assert_eq!(output, "hello world !")
 }
 
+#[test]
+fn interpolation_flag() {
+    let release = true;
+    let verbose = false;
+    let cmd = cmd!("cargo build {release:--release} {verbose:-v} --locked");
+    assert_eq!(cmd.get_args(), ["build", "--release", "--locked"]);
+    let cmd = cmd!("cargo build {verbose:--release} {!verbose:-v}");
+    assert_eq!(cmd.get_args(), ["build", "-v"]);
+
+    let output = cmd!("echo {release:--release} {verbose:--verbose} done").read().unwrap();
+    assert_eq!(output, "--release done");
+}
+
+#[test]
+fn interpolation_expr() {
+    let count = 91;
+    let src = Path::new("src/lib.rs");
+    let names = ["a/", "b"];
+    let cmd = cmd!("echo {count + 1} {src.display()} {names[0].trim_end_matches(\"/\")}");
+    assert_eq!(cmd.to_string(), "echo 92 src/lib.rs a");
+    let cmd =
+        cmd!("echo {if count > 0 { \"pos\" } else { \"neg\" }} {std::path::Path::new(\"x\")}");
+    assert_eq!(cmd.to_string(), "echo pos x");
+    let cmd = cmd!("echo {names.iter().rev()...} {count > 0:--verbose} -j{count / 10}");
+    assert_eq!(cmd.to_string(), "echo b a/ --verbose -j9");
+}
+
+#[test]
+fn interpolation_format_spec() {
+    let id = 7;
+    let mask = 255;
+    let ratio = 0.5;
+    let name = "x";
+    let cmd = cmd!("tool --id={id:04} {mask:#x} {ratio:.3} [{name:>3}] {name:?}");
+    assert_eq!(cmd.to_string(), r#"tool --id=0007 0xff 0.500 "[  x]" "x""#);
+    assert_eq!(cmd.get_args()[4], r#""x""#);
+}
+
+#[test]
+fn interpolation_path() {
+    let path = PathBuf::from("dir with spaces").join("file.txt");
+    let paths = vec![path.clone(), PathBuf::from("other")];
+    let cmd = cmd!("echo {path} {paths...} --out={path}");
+    assert_eq!(
+        cmd.to_string(),
+        r#"echo "dir with spaces/file.txt" "dir with spaces/file.txt" other "--out=dir with spaces/file.txt""#
+    );
+    assert_eq!(cmd.get_args()[0], path.as_os_str());
+    assert_eq!(cmd.get_args()[3], "--out=dir with spaces/file.txt");
+    assert_eq!(
+        cmd.read().unwrap(),
+        "dir with spaces/file.txt dir with spaces/file.txt other --out=dir with spaces/file.txt"
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn interpolation_non_utf8() {
+    use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+    let name = std::ffi::OsString::from_vec(b"caf\xe9".to_vec());
+    let path = Path::new("dir").join(&name);
+    let cmd = cmd!("printf %s {path}");
+    assert_eq!(cmd.get_args()[1].as_bytes(), b"dir/caf\xe9");
+    let bytes = cmd.output().unwrap().stdout;
+    assert_eq!(bytes, b"dir/caf\xe9");
+}
+
 #[test]
 fn exit_status() {
     let err = cmd!("false").read().unwrap_err();
     assert_eq!(err.to_string(), "command `false` failed, exit code: 1");
 }
 
+#[test]
+fn non_utf8_stdout() {
+    let err = cmd!("printf '\\377hello'").read().unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "command `printf \\377hello` produced invalid utf8, \
+         invalid utf-8 sequence of 1 bytes from index 0, stdout: \"\u{fffd}hello\""
+    );
+}
+
+#[test]
+fn read_bytes() {
+    let bytes = cmd!("printf '\\377hello\n\n'").read_bytes().unwrap();
+    assert_eq!(bytes, b"\xffhello\n\n");
+
+    let bytes = cmd!("printf '\\0\\1' | cat").read_bytes().unwrap();
+    assert_eq!(bytes, [0, 1]);
+
+    cmd!("false").read_bytes().unwrap_err();
+    let bytes = cmd!("sh -c 'printf x; false'").ignore_status().read_bytes().unwrap();
+    assert_eq!(bytes, b"x");
+}
+
+#[test]
+fn read_combined() {
+    let script = "echo 1; echo 2 >&2; echo 3; echo 4 >&2";
+    let out = cmd!("sh -c {script}").read_combined().unwrap();
+    assert_eq!(out, "1\n2\n3\n4");
+
+    let err = cmd!("sh -c 'echo oops >&2; exit 3'").read_combined().unwrap_err();
+    assert!(err.to_string().ends_with("exit code: 3"), "{}", err);
+    let out = cmd!("sh -c 'echo oops >&2; exit 3'").ignore_status().read_combined().unwrap();
+    assert_eq!(out, "oops");
+
+    let err = cmd!("echo hello").pipe(cmd!("cat")).read_combined().unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "command `echo hello | cat` failed, can't capture stderr of a pipeline"
+    );
+}
+
+#[test]
+fn non_utf8_stderr() {
+    let cmd = cmd!("sh -c 'printf \\\\377 >&2'");
+    let expected = format!(
+        "command `{}` produced invalid utf8, \
+         invalid utf-8 sequence of 1 bytes from index 0, stderr: \"\u{fffd}\"",
+        cmd
+    );
+    let err = cmd.read_stderr().unwrap_err();
+    assert_eq!(err.to_string(), expected);
+    let stderr = cmd!("sh -c 'echo oops >&2'").read_stderr().unwrap();
+    assert_eq!(stderr, "oops");
+}
+
 #[test]
 fn ignore_status() {
     let output = cmd!("false").ignore_status().read().unwrap();
     assert_eq!(output, "");
 }
 
+#[test]
+fn ok_statuses() {
+    cmd!("sh -c 'exit 1'").ok_statuses(&[1, 2]).run().unwrap();
+    let err = cmd!("sh -c 'exit 3'").ok_statuses(&[1, 2]).run().unwrap_err();
+    assert_eq!(err.to_string(), "command `sh -c \"exit 3\"` failed, exit code: 3");
+    cmd!("sh -c 'exit 3'").ignore_status().run().unwrap();
+    let out = cmd!("diff Cargo.toml LICENSE-MIT").ok_statuses(&[1]).read().unwrap();
+    assert!(!out.is_empty());
+}
+
+#[test]
+fn output() {
+    let output = cmd!("sh -c 'printf out; printf err >&2'").output().unwrap();
+    assert_eq!(output.stdout, b"out");
+    assert_eq!(output.stderr, b"err");
+    assert!(output.status.success());
+
+    let err = cmd!("false").output().unwrap_err();
+    assert_eq!(err.to_string(), "command `false` failed, exit code: 1");
+    let output = cmd!("false").ignore_status().output().unwrap();
+    assert_eq!(output.status.code(), Some(1));
+
+    let output = cmd!("echo hello").pipe(cmd!("tr a-z A-Z")).output().unwrap();
+    assert_eq!(output.stdout, b"HELLO\n");
+}
+
 #[test]
 fn read_stderr() {
     let output = cmd!("git fail").ignore_status().read_stderr().unwrap();
     assert!(output.contains("fail"));
+
+    let err = cmd!("echo hello").pipe(cmd!("cat")).read_stderr().unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "command `echo hello | cat` failed, can't read stderr of a pipeline"
+    );
+}
+
+#[test]
+fn run_echo_ordering() {
+    const CHILD: &str = "XSHELL_RUN_ECHO_ORDERING_CHILD";
+    if std::env::var_os(CHILD).is_some() {
+        print!("before ");
+        cmd!("echo hello").run().unwrap();
+        print!("before quiet ");
+        cmd!("echo hello").quiet().run().unwrap();
+        return;
+    }
+
+    let exe = std::env::current_exe().unwrap();
+    let _e = pushenv(CHILD, "1");
+    let output = cmd!("{exe} run_echo_ordering --exact --nocapture").read().unwrap();
+    assert!(output.contains("before $ echo hello\nhello\n"), "{}", output);
+    assert!(output.contains("before quiet hello\n"), "{}", output);
+}
+
+#[test]
+fn quiet() {
+    const CHILD: &str = "XSHELL_QUIET_CHILD";
+    if std::env::var_os(CHILD).is_some() {
+        cmd!("echo loud").run().unwrap();
+        cmd!("echo hello").quiet().run().unwrap();
+        return;
+    }
+
+    let exe = std::env::current_exe().unwrap();
+    let _e = pushenv(CHILD, "1");
+    let output = cmd!("{exe} quiet --exact --nocapture").read().unwrap();
+    assert!(output.contains("$ echo loud\nloud\nhello\n"), "{}", output);
+}
+
+#[test]
+fn log_prefix() {
+    const CHILD: &str = "XSHELL_LOG_PREFIX_CHILD";
+    if std::env::var_os(CHILD).is_some() {
+        let script = "echo out; echo err >&2; printf partial";
+        cmd!("sh -c {script}").log_prefix("[a] ").run().unwrap();
+        let err = cmd!("false").log_prefix("[b] ").run().unwrap_err();
+        assert_eq!(err.to_string(), "command `false` failed, exit code: 1");
+        return;
+    }
+
+    let exe = std::env::current_exe().unwrap();
+    let _e = pushenv(CHILD, "1");
+    let output = cmd!("{exe} log_prefix --exact --nocapture").output().unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stdout.contains("[a] $ sh -c"), "{}", stdout);
+    assert!(stdout.contains("[a] out\n[a] partial\n"), "{}", stdout);
+    assert!(stdout.contains("[b] $ false\n"), "{}", stdout);
+    assert!(stderr.contains("[a] err\n"), "{}", stderr);
+}
+
+#[test]
+fn retry() {
+    const CHILD: &str = "XSHELL_RETRY_CHILD";
+    if let Some(counter) = std::env::var_os(CHILD) {
+        // Fails twice, then succeeds.
+        let script = "echo x >> \"$0\"; test $(wc -l < \"$0\") -ge 3";
+        let delay = Duration::from_millis(10);
+        cmd!("sh -c {script} {counter}").retry(3, delay).run().unwrap();
+        rm_rf(&counter).unwrap();
+        cmd!("sh -c {script} {counter}").retry(2, delay).run().unwrap_err();
+        rm_rf(&counter).unwrap();
+        let script = format!("{} && echo ok", script);
+        let out = cmd!("sh -c {script} {counter}").retry(5, delay).read().unwrap();
+        assert_eq!(out, "ok");
+        assert_eq!(read_file(&counter).unwrap().lines().count(), 3);
+        rm_rf(&counter).unwrap();
+        #[cfg(feature = "extra-cmd")]
+        {
+            let out = cmd!("sh -c {script} {counter}").retry(5, delay).read_skip(1).unwrap();
+            assert_eq!(out, "k");
+            rm_rf(&counter).unwrap();
+        }
+        let lines = cmd!("sh -c {script} {counter}").retry(5, delay).read_lines().unwrap();
+        assert_eq!(lines, ["ok"]);
+        rm_rf(&counter).unwrap();
+        // Methods which return the exit status run the command once.
+        #[cfg(feature = "extra-cmd")]
+        {
+            let cmd = cmd!("sh -c {script} {counter}").retry(5, delay);
+            assert!(!cmd.run_stdout(|_chunk| Ok(())).unwrap().success());
+            assert_eq!(read_file(&counter).unwrap().lines().count(), 1);
+        }
+        return;
+    }
+
+    let exe = std::env::current_exe().unwrap();
+    let counter = temp_dir().unwrap();
+    let _e = pushenv(CHILD, counter.path().join("counter"));
+    let output = cmd!("{exe} retry --exact --nocapture").read().unwrap();
+    assert!(output.contains("counter (attempt 2/3)\n"), "{}", output);
+    assert!(output.contains("counter (attempt 3/3)\n"), "{}", output);
+    assert!(output.contains("counter (attempt 2/2)\n"), "{}", output);
+    assert!(!output.contains("attempt 3/2"), "{}", output);
+}
+
+#[test]
+fn echo_hook() {
+    const CHILD: &str = "XSHELL_ECHO_HOOK_CHILD";
+    if std::env::var_os(CHILD).is_some() {
+        set_echo_hook(|cmd| {
+            // Hooks can run commands too.
+            let n = cmd!("echo 1").quiet().read().unwrap();
+            eprintln!("hook {}: {}", n, cmd)
+        });
+        cmd!("echo hello").run().unwrap();
+        cmd!("echo quiet").quiet().run().unwrap();
+        set_echo_hook(default_echo_hook);
+        cmd!("echo bye").run().unwrap();
+        return;
+    }
+
+    let exe = std::env::current_exe().unwrap();
+    let _e = pushenv(CHILD, "1");
+    let output = cmd!("{exe} echo_hook --exact --nocapture").output().unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("hook 1: echo hello\n"), "{}", stderr);
+    assert!(!stderr.contains("quiet"), "{}", stderr);
+    assert!(!stdout.contains("$ echo hello"), "{}", stdout);
+    assert!(stdout.contains("hello\nquiet\n$ echo bye\nbye\n"), "{}", stdout);
+}
+
+#[test]
+fn echo_to_stderr() {
+    const CHILD: &str = "XSHELL_ECHO_TO_STDERR_CHILD";
+    if std::env::var_os(CHILD).is_some() {
+        cmd!("echo hello").echo_to(EchoTarget::Stderr).run().unwrap();
+        #[cfg(feature = "extra-cmd")]
+        {
+            run_in_dirs(&["src".into()], || cmd!("true").echo_to(EchoTarget::Stderr));
+            run_in_dirs(&["tests".into()], || cmd!("true").quiet());
+        }
+        return;
+    }
+
+    let exe = std::env::current_exe().unwrap();
+    let _e = pushenv(CHILD, "1");
+    let output = cmd!("{exe} echo_to_stderr --exact --nocapture").output().unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stdout.contains("hello\n"), "{}", stdout);
+    assert!(!stdout.contains("$ echo hello"), "{}", stdout);
+    assert!(stderr.contains("$ echo hello\n"), "{}", stderr);
+    #[cfg(feature = "extra-cmd")]
+    {
+        assert!(stderr.contains("$ cd src\n$ true\n"), "{}", stderr);
+        assert!(!stdout.contains("$ cd"), "{}", stdout);
+        assert!(!stderr.contains("$ cd tests"), "{}", stderr);
+    }
+}
+
+#[test]
+fn exit_code() {
+    assert_eq!(xshell::exit_code::EX_USAGE, 64);
+    assert_eq!(xshell::exit_code::EX_CONFIG, 78);
+    let err = cmd!("sh -c 'exit 75'").run().unwrap_err();
+    assert_eq!(err.to_string(), "command `sh -c \"exit 75\"` failed, exit code: 75");
+    cmd!("sh -c 'exit 75'").ok_statuses(&[xshell::exit_code::EX_TEMPFAIL]).run().unwrap();
 }
 
 #[test]
 fn unknown_command() {
     let err = cmd!("nope no way").read().unwrap_err();
-    assert_eq!(err.to_string(), "command not found: `nope`");
+    assert_eq!(
+        err.to_string(),
+        "command `nope no way` failed: `nope` not found, is it installed and on PATH?"
+    );
+    let source = std::error::Error::source(&err).unwrap();
+    assert_eq!(
+        source.downcast_ref::<std::io::Error>().unwrap().kind(),
+        std::io::ErrorKind::NotFound
+    );
+}
+
+#[test]
+fn error_source() {
+    use std::{error::Error, io::ErrorKind};
+
+    let err = cmd!("nope").run().unwrap_err();
+    assert_eq!(err.io_kind(), Some(ErrorKind::NotFound));
+
+    let err = read_file("./does/not/exist").unwrap_err();
+    assert_eq!(err.io_kind(), Some(ErrorKind::NotFound));
+    assert!(err.source().unwrap().is::<std::io::Error>());
+
+    let err = cmd!("false").run().unwrap_err();
+    assert_eq!(err.io_kind(), None);
+    assert!(err.source().is_none());
+
+    let err = cmd!("printf '\\377'").read().unwrap_err();
+    assert_eq!(err.io_kind(), None);
+    assert!(err.source().unwrap().is::<std::string::FromUtf8Error>());
+
+    #[cfg(unix)]
+    with_temp_dir(|dir| {
+        let script = dir.join("script");
+        write_file(&script, "").unwrap();
+        let err = cmd!("{script}").run().unwrap_err();
+        assert_eq!(err.io_kind(), Some(ErrorKind::PermissionDenied));
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[cfg(feature = "extra-cmd")]
+#[test]
+fn run_interactive() {
+    cmd!("true").run_interactive().unwrap();
+
+    let err = cmd!("false").run_interactive().unwrap_err();
+    assert_eq!(err.to_string(), "command `false` failed, exit code: 1");
+
+    let err = cmd!("cat").stdin("hello").run_interactive().unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "command `cat` failed, stdin is set, can't connect it to the terminal"
+    );
+
+    let err = cmd!("echo hello | cat").run_interactive().unwrap_err();
+    assert!(err.to_string().ends_with("can't run a pipeline interactively"), "{}", err);
+}
+
+#[test]
+fn run_failure() {
+    let err = cmd!("false").run().unwrap_err();
+    assert_eq!(err.to_string(), "command `false` failed, exit code: 1");
+
+    let err = cmd!("nope no way").run().unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "command `nope no way` failed: `nope` not found, is it installed and on PATH?"
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn run_killed_by_signal() {
+    let script = "kill -9 $$";
+    let err = cmd!("sh -c {script}").run().unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "command `sh -c \"kill -9 $$\"` failed, killed by signal 9 (SIGKILL)"
+    );
+    let err = cmd!("sh -c {script}").read().unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "command `sh -c \"kill -9 $$\"` failed, killed by signal 9 (SIGKILL)"
+    );
+}
+
+#[test]
+fn to_sh_string() {
+    let cmd =
+        Cmd::new("/usr/bin/my app").args(["a b", "it's", "", "a|b", "$x", "*.rs", "\t", "-v"]);
+    assert_eq!(
+        cmd.to_sh_string(),
+        "'/usr/bin/my app' 'a b' 'it'\\''s' '' 'a|b' '$x' '*.rs' '\t' -v"
+    );
+    let cmd = Cmd::new("echo").arg("{}").pipe(cmd!("grep --color=auto x"));
+    assert_eq!(cmd.to_sh_string(), "echo '{}' | grep --color=auto x");
+
+    let args = ["a b", "it's", "$HOME", "`x`", "a\\b", "!", "~"];
+    let script = Cmd::new("printf").arg("%s\n").args(args).to_sh_string();
+    assert_eq!(cmd!("sh -c {script}").read().unwrap().lines().collect::<Vec<_>>(), args);
+}
+
+#[cfg(feature = "extra-cmd")]
+#[test]
+fn to_powershell_string() {
+    let cmd = Cmd::new(r"C:\Program Files\app.exe").args(["a b", "it's", "", "x,y", "-v"]);
+    assert_eq!(
+        cmd.to_powershell_string(),
+        r"& 'C:\Program Files\app.exe' 'a b' 'it''s' '' 'x,y' -v"
+    );
+    let cmd = cmd!("echo $HOME").pipe(cmd!("sort"));
+    assert_eq!(cmd.to_powershell_string(), "echo '$HOME' | sort");
+}
+
+#[cfg(feature = "extra-cmd")]
+#[test]
+fn to_cmd_string() {
+    let cmd = Cmd::new("echo").args(["a b", r#"say "hi""#, "", "a&b", "(x)^"]);
+    assert_eq!(cmd.to_cmd_string(), r#"echo "a b" "say ""hi""" "" a^&b ^(x^)^^"#);
+}
+
+#[test]
+fn display_shows_all_args() {
+    let args = (0..1000).map(|i| format!("arg{}", i)).collect::<Vec<_>>();
+    let expected = format!("echo {}", args.join(" "));
+    let cmd = cmd!("echo {args...}");
+    assert_eq!(cmd.to_string(), expected);
+}
+
+#[test]
+fn empty_program() {
+    let program = "";
+    let err = cmd!("{program} --version").read().unwrap_err();
+    assert_eq!(err.to_string(), "command ` --version` failed, empty program name");
+
+    let err = Cmd::new("").run().unwrap_err();
+    assert_eq!(err.to_string(), "command `` failed, empty program name");
 }
 
 #[test]
@@ -92,92 +566,1493 @@ fn args_with_spaces() {
 }
 
 #[test]
-fn escape() {
-    let output = cmd!("echo \\hello\\ '\\world\\'").read().unwrap();
-    assert_eq!(output, r#"\hello\ \world\"#)
+fn is_tty() {
+    // Whether the test itself runs in a terminal is unknown, so check only
+    // that a piped stdout is detected as such.
+    const CHILD: &str = "XSHELL_IS_TTY_CHILD";
+    if std::env::var_os(CHILD).is_some() {
+        assert!(!xshell::is_tty_stdout());
+        return;
+    }
+
+    let exe = std::env::current_exe().unwrap();
+    let _e = pushenv(CHILD, "1");
+    cmd!("{exe} is_tty --exact").read().unwrap();
 }
 
 #[test]
-fn stdin_redirection() {
-    let lines = "\
-foo
-baz
-bar
-";
-    let output = cmd!("sort").stdin(lines).read().unwrap();
-    assert_eq!(
-        output,
-        "\
-bar
-baz
-foo"
-    )
+fn test_pushenv_prev() {
+    let (_e1, prev) = pushenv_prev("XSHELL_TEST_PUSHENV_PREV", "1");
+    assert_eq!(prev, None);
+    {
+        let (_e2, prev) = pushenv_prev("XSHELL_TEST_PUSHENV_PREV", "2");
+        assert_eq!(prev.as_deref(), Some(OsStr::new("1")));
+    }
+    assert_eq!(std::env::var("XSHELL_TEST_PUSHENV_PREV").unwrap(), "1");
 }
 
+#[cfg(feature = "extra-fs")]
 #[test]
-fn test_pushd() {
-    let d1 = cwd().unwrap();
+fn test_path_dirs() {
+    let dirs = vec![PathBuf::from("/usr/bin"), PathBuf::from("/opt/my tools/bin")];
+    let path = join_path_dirs(&dirs).unwrap();
     {
-        let _p = pushd("xshell-macros").unwrap();
-        let d2 = cwd().unwrap();
-        assert_eq!(d2, d1.join("xshell-macros"));
-        {
-            let _p = pushd("src").unwrap();
-            let d3 = cwd().unwrap();
-            assert_eq!(d3, d1.join("xshell-macros/src"));
-        }
-        let d4 = cwd().unwrap();
-        assert_eq!(d4, d1.join("xshell-macros"));
+        let _e = pushenv("PATH", &path);
+        assert_eq!(path_dirs(), dirs);
     }
-    let d5 = cwd().unwrap();
-    assert_eq!(d5, d1);
+
+    let sep = if cfg!(windows) { ";" } else { ":" };
+    let bad = PathBuf::from(format!("a{}b", sep));
+    let err = join_path_dirs(std::slice::from_ref(&bad)).unwrap_err();
+    assert_eq!(err.to_string(), format!("`{}`: path contains the `PATH` separator", bad.display()));
 }
 
 #[test]
-fn pushd_parent_dir() {
-    let current = cwd().unwrap();
-    let dirname = current.file_name().unwrap();
-    let _d = pushd("..").unwrap();
-    let _d = pushd(dirname).unwrap();
-    assert_eq!(cwd().unwrap(), current);
+fn shell() {
+    let out = Cmd::shell("echo hello").read().unwrap();
+    assert_eq!(out.trim(), "hello");
+
+    let err = Cmd::shell("exit 3").run().unwrap_err();
+    assert!(err.to_string().ends_with("failed, exit code: 3"), "{}", err);
+
+    if cfg!(unix) {
+        let out = Cmd::shell("echo hello | tr a-z A-Z").read().unwrap();
+        assert_eq!(out, "HELLO");
+    }
 }
 
 #[test]
-fn test_pushd_lock() {
-    let t1 = thread::spawn(|| {
-        let _p = pushd("cbench").unwrap();
-        sleep_ms(20);
-    });
-    sleep_ms(10);
+fn args_file() {
+    let dir = temp_dir().unwrap();
+    let path = dir.path().join("args");
+    write_file(&path, "# targets\nfoo\n\n  \nbar baz\r\n  # not a target\n").unwrap();
+    let cmd = cmd!("echo").args_file(&path).unwrap();
+    assert_eq!(cmd.to_string(), r#"echo foo "bar baz""#);
+    rm_rf(&path).unwrap();
 
-    let t2 = thread::spawn(|| {
-        let _p = pushd("cbench").unwrap();
-        sleep_ms(30);
-    });
+    let err = cmd!("echo").args_file(&path).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        format!("`{}`: no such file or directory (os error 2)", path.display())
+    );
+}
 
-    t1.join().unwrap();
-    t2.join().unwrap();
+#[test]
+fn extend_last_arg() {
+    let cmd = Cmd::new("cp").arg("main").extend_last_arg(".rs").arg("out");
+    assert_eq!(cmd.extend_last_arg(".bak").to_string(), "cp main.rs out.bak");
+    assert_eq!(Cmd::new("python").extend_last_arg("3").to_string(), "python3");
 }
 
-const VAR: &str = "SPICA";
+#[test]
+fn cmd_env() {
+    let _e = pushenv("XSHELL_TEST_CMD_ENV", "inherited");
+    let script = "echo $XSHELL_TEST_CMD_ENV-$XSHELL_TEST_CMD_ENV2";
+    let out = cmd!("sh -c {script}").env("XSHELL_TEST_CMD_ENV", "overridden").read().unwrap();
+    assert_eq!(out, "overridden-");
+    let out = cmd!("sh -c {script}")
+        .envs(vec![("XSHELL_TEST_CMD_ENV", "a"), ("XSHELL_TEST_CMD_ENV2", "b")])
+        .read()
+        .unwrap();
+    assert_eq!(out, "a-b");
+    let out = cmd!("sh -c {script}").env_remove("XSHELL_TEST_CMD_ENV").read().unwrap();
+    assert_eq!(out, "-");
+    assert_eq!(std::env::var("XSHELL_TEST_CMD_ENV").unwrap(), "inherited");
+}
 
 #[test]
-fn test_pushenv() {
-    let e1 = std::env::var_os(VAR);
-    {
-        let _e = pushenv(VAR, "1");
-        let e2 = std::env::var_os(VAR);
-        assert_eq!(e2, Some("1".into()));
-        {
-            let _e = pushenv(VAR, "2");
-            let e3 = std::env::var_os(VAR);
-            assert_eq!(e3, Some("2".into()));
-        }
-        let e4 = std::env::var_os(VAR);
-        assert_eq!(e4, e2);
-    }
-    let e5 = std::env::var_os(VAR);
-    assert_eq!(e5, e1);
+fn hermetic_env() {
+    let _e1 = pushenv("XSHELL_TEST_HERMETIC_KEEP", "1");
+    let _e2 = pushenv("XSHELL_TEST_HERMETIC_DROP", "1");
+    let env = cmd!("env").hermetic_env(&["XSHELL_TEST_HERMETIC_KEEP", "NOT_SET"]).read().unwrap();
+    let mut keys = env.lines().map(|it| it.split('=').next().unwrap()).collect::<Vec<_>>();
+    keys.sort_unstable();
+    assert_eq!(keys, ["PATH", "XSHELL_TEST_HERMETIC_KEEP"]);
+}
+
+#[test]
+fn env_clear() {
+    let _e = pushenv("XSHELL_TEST_ENV_CLEAR", "1");
+    let env = cmd!("env").env("A", "1").env_clear().env("B", "2").read().unwrap();
+    assert_eq!(env.lines().collect::<Vec<_>>(), ["A=1", "B=2"]);
+}
+
+#[test]
+fn arg_if() {
+    let cmd = cmd!("cargo build").arg_if(true, "--release").arg_if(false, "--locked");
+    assert_eq!(cmd.to_string(), "cargo build --release");
+
+    let cmd = cmd!("cargo publish")
+        .args_if(false, ["--dry-run", "--allow-dirty"])
+        .args_if(true, &["--token", "x"]);
+    assert_eq!(cmd.to_string(), "cargo publish --token x");
+}
+
+#[test]
+fn arg_opt() {
+    let target = Some("wasm32-unknown-unknown");
+    let cmd = cmd!("cargo build").arg_opt(target.map(|it| format!("--target={}", it)));
+    assert_eq!(cmd.to_string(), "cargo build --target=wasm32-unknown-unknown");
+
+    let features: Option<&str> = None;
+    let cmd = cmd!("cargo build").arg_opt(features);
+    assert_eq!(cmd.to_string(), "cargo build");
+}
+
+#[test]
+fn huge_output_does_not_deadlock() {
+    let script = "yes out | head -c 4000000; yes err | head -c 4000000 >&2; yes out | head -c 10";
+    let stdout = cmd!("sh -c {script}").read().unwrap();
+    assert_eq!(stdout.len(), 4000010);
+
+    let output = cmd!("sh -c {script}").output().unwrap();
+    assert_eq!(output.stdout.len(), 4000010);
+    assert_eq!(output.stderr.len(), 4000000);
+
+    let input = "x".repeat(4000000);
+    let stdout = cmd!("cat").stdin(&input).read().unwrap();
+    assert_eq!(stdout, input);
+}
+
+#[cfg(feature = "extra-cmd")]
+#[test]
+fn run_split_streaming() {
+    let script = "echo out1; echo err1 >&2; echo out2; echo err2 >&2; exit 2";
+    let mut out = Vec::new();
+    let mut err = Vec::new();
+    let status = cmd!("sh -c {script}")
+        .run_split_streaming(|line| out.push(line.to_string()), |line| err.push(line.to_string()))
+        .unwrap();
+    assert_eq!(status.code(), Some(2));
+    assert_eq!(out, ["out1", "out2"]);
+    assert_eq!(err, ["err1", "err2"]);
+
+    let err = cmd!("false | true").run_split_streaming(|_line| (), |_line| ()).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "command `false | true` failed, can't capture stderr of a pipeline"
+    );
+}
+
+#[cfg(feature = "extra-cmd")]
+#[test]
+fn read_tail() {
+    let lines = cmd!("seq 100").read_tail(3).unwrap();
+    assert_eq!(lines, ["98", "99", "100"]);
+    let lines = cmd!("seq 2").read_tail(3).unwrap();
+    assert_eq!(lines, ["1", "2"]);
+
+    let err = cmd!("false").read_tail(3).unwrap_err();
+    assert_eq!(err.to_string(), "command `false` failed, exit code: 1");
+
+    let lines = cmd!("seq 100").pipe(cmd!("grep 5")).read_tail(2).unwrap();
+    assert_eq!(lines, ["85", "95"]);
+    let err = cmd!("seq 100").pipe(cmd!("grep x")).read_tail(2).unwrap_err();
+    assert_eq!(err.to_string(), "pipeline failed at stage 2 (`grep`): exit code 1");
+}
+
+#[cfg(feature = "flate2")]
+#[test]
+fn read_gz() {
+    let stdout = cmd!("gzip -c").stdin("hello").read_gz().unwrap();
+    assert_eq!(stdout, b"hello");
+
+    let err = cmd!("echo not gzip").read_gz().unwrap_err();
+    assert!(err.to_string().starts_with("command `echo not gzip` failed, "), "{}", err);
+
+    let stdout = cmd!("gzip -c").stdin("hello").pipe(cmd!("cat")).read_gz().unwrap();
+    assert_eq!(stdout, b"hello");
+    let err = cmd!("false | gzip -c").read_gz().unwrap_err();
+    assert_eq!(err.to_string(), "pipeline failed at stage 1 (`false`): exit code 1");
+}
+
+#[cfg(feature = "extra-cmd")]
+#[test]
+fn read_split_by() {
+    let records = cmd!("printf 'a\\0b c\\0\\377\\0'").read_split_by(0).unwrap();
+    assert_eq!(records, [&b"a"[..], b"b c", b"\xff"]);
+    let records = cmd!("printf 'a;;b'").read_split_by(b';').unwrap();
+    assert_eq!(records, [&b"a"[..], b"", b"b"]);
+
+    let records = cmd!("printf 'x,y,'").read_split_by_char(',').unwrap();
+    assert_eq!(records, ["x", "y"]);
+    assert!(cmd!("printf '\\377'").read_split_by_char(',').is_err());
+
+    let records =
+        cmd!("printf 'b,a,'").pipe(cmd!("tr , '\\n'")).pipe(cmd!("sort")).read_split_by(b'\n');
+    assert_eq!(records.unwrap(), [&b"a"[..], b"b"]);
+}
+
+#[test]
+fn pipe() {
+    let out = cmd!("printf 'b\\na\\nb\\n'").pipe(cmd!("sort")).pipe(cmd!("uniq")).read().unwrap();
+    assert_eq!(out, "a\nb");
+    let out = cmd!("yes").pipe(cmd!("head -n 2")).read().unwrap();
+    assert_eq!(out, "y\ny");
+    cmd!("echo hello").pipe(cmd!("cat")).run().unwrap();
+
+    let cmd = cmd!("echo hello").pipe(cmd!("grep bye")).pipe(cmd!("cat"));
+    assert_eq!(cmd.to_string(), "echo hello | grep bye | cat");
+    let err = cmd.read().unwrap_err();
+    assert_eq!(err.to_string(), "pipeline failed at stage 2 (`grep`): exit code 1");
+    let err = cmd!("false").pipe(cmd!("cat")).run().unwrap_err();
+    assert_eq!(err.to_string(), "pipeline failed at stage 1 (`false`): exit code 1");
+    let err = cmd!("echo hello").pipe(cmd!("nope-no-such-program")).read().unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "pipeline failed at stage 2: `nope-no-such-program` not found, \
+         is it installed and on PATH?"
+    );
+}
+
+#[test]
+fn macro_pipe() {
+    let pattern = "b";
+    let cmd = cmd!("printf 'a\\nb\\nbb\\n' | grep {pattern} | wc -l");
+    assert_eq!(cmd.to_string(), "printf a\\nb\\nbb\\n | grep b | wc -l");
+    assert_eq!(cmd.read().unwrap().trim(), "2");
+
+    let out = cmd!("echo 'a | b'").read().unwrap();
+    assert_eq!(out, "a | b");
+}
+
+#[cfg(feature = "extra-cmd")]
+#[test]
+fn cmd_parse() {
+    let cmd =
+        Cmd::parse(r#"  prog plain 'single $x' "double \"\$\a" esc\ aped '' x"y"'z'  "#).unwrap();
+    assert_eq!(cmd.get_program(), "prog");
+    assert_eq!(cmd.get_args(), ["plain", "single $x", "double \"$\\a", "esc aped", "", "xyz"]);
+    assert_eq!(Cmd::parse("echo hello").unwrap().read().unwrap(), "hello");
+
+    let err = Cmd::parse("echo 'hello").unwrap_err();
+    assert_eq!(err.to_string(), "can't parse command line `echo 'hello`: unbalanced single quote");
+    let err = Cmd::parse(r#"echo "hello\""#).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        r#"can't parse command line `echo "hello\"`: unbalanced double quote"#
+    );
+    let err = Cmd::parse("echo \\").unwrap_err();
+    assert_eq!(err.to_string(), "can't parse command line `echo \\`: trailing backslash");
+    let err = Cmd::parse("  ").unwrap_err();
+    assert_eq!(err.to_string(), "can't parse command line `  `: empty command line");
+}
+
+#[test]
+fn secret_arg() {
+    let cmd = cmd!("echo token:").secret_arg("hunter2").arg("done");
+    assert_eq!(cmd.to_string(), "echo token: <secret> done");
+    #[cfg(feature = "extra-cmd")]
+    assert_eq!(cmd.to_powershell_string(), "echo token: <secret> done");
+    assert_eq!(cmd.clone().read().unwrap(), "token: hunter2 done");
+
+    let err = cmd!("sh -c").secret_arg("exit 1").read().unwrap_err();
+    assert_eq!(err.to_string(), "command `sh -c <secret>` failed, exit code: 1");
+
+    let cmd = cmd!("echo").pipe(cmd!("curl -H").secret_arg("Authorization: hunter2"));
+    let debug = format!("{:?}", cmd);
+    assert!(!debug.contains("hunter2"), "{}", debug);
+    assert!(debug.contains(r#"args: ["curl", "-H", "<secret>"]"#), "{}", debug);
+}
+
+#[test]
+fn read_lines() {
+    let lines = cmd!("printf 'a\\r\\nb c\\n\\nd\\n'").read_lines().unwrap();
+    assert_eq!(lines, ["a", "b c", "", "d"]);
+    let lines = cmd!("true").read_lines().unwrap();
+    assert!(lines.is_empty());
+}
+
+#[cfg(feature = "extra-cmd")]
+#[test]
+fn read_parse() {
+    let n: u32 = cmd!("echo 92").read_parse().unwrap();
+    assert_eq!(n, 92);
+
+    let err = cmd!("echo ninety-two").read_parse::<u32>().unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "command `echo ninety-two` failed, can't parse output \"ninety-two\": invalid digit found in string"
+    );
+}
+
+#[cfg(feature = "extra-cmd")]
+#[test]
+fn read_skip() {
+    let stdout = cmd!("printf '\\377\\376hello'").read_skip(2).unwrap();
+    assert_eq!(stdout, "hello");
+    let stdout = cmd!("echo hi").read_skip(10).unwrap();
+    assert_eq!(stdout, "");
+    let stdout = cmd!("echo hello").pipe(cmd!("tr a-z A-Z")).read_skip(1).unwrap();
+    assert_eq!(stdout, "ELLO");
+}
+
+#[cfg(feature = "extra-cmd")]
+#[test]
+fn read_truncated() {
+    let (output, truncated) = cmd!("yes").read_truncated(8).unwrap();
+    assert_eq!(output, "y\ny\ny\ny\n");
+    assert!(truncated);
+
+    let (output, truncated) = cmd!("echo hello").read_truncated(8).unwrap();
+    assert_eq!(output, "hello");
+    assert!(!truncated);
+
+    let (output, truncated) = cmd!("yes").pipe(cmd!("tr y n")).read_truncated(4).unwrap();
+    assert_eq!(output, "n\nn\n");
+    assert!(truncated);
+    let (output, truncated) =
+        cmd!("echo hello").pipe(cmd!("tr a-z A-Z")).read_truncated(8).unwrap();
+    assert_eq!(output, "HELLO");
+    assert!(!truncated);
+}
+
+#[cfg(feature = "extra-cmd")]
+#[test]
+fn run_full() {
+    let output = Cmd::new("sh").arg("-c").arg("echo out; echo err >&2; exit 3").run_full().unwrap();
+    assert_eq!(output.stdout, "out\n");
+    assert_eq!(output.stderr, "err\n");
+    assert_eq!(output.status.code(), Some(3));
+    assert!(output.duration > Duration::from_secs(0));
+
+    let err = cmd!("echo hello").pipe(cmd!("cat")).run_full().unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "command `echo hello | cat` failed, can't capture stderr of a pipeline"
+    );
+}
+
+#[cfg(feature = "extra-cmd")]
+#[test]
+fn read_expect() {
+    cmd!("echo hello").read_expect("hello\n").unwrap();
+
+    let err = cmd!("printf 'a\\nb\\nc'").read_expect("a\nx\nc").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "\
+command `printf a\\nb\\nc` produced unexpected output:
+  a
+- x
++ b
+  c
+"
+    );
+}
+
+#[cfg(feature = "extra-cmd")]
+#[test]
+fn run_stdout() {
+    let mut buf = Vec::new();
+    let status = cmd!("echo hello")
+        .run_stdout(|chunk| {
+            buf.extend_from_slice(chunk);
+            Ok(())
+        })
+        .unwrap();
+    assert!(status.success());
+    assert_eq!(buf, b"hello\n");
+
+    let err = cmd!("yes")
+        .run_stdout(|_chunk| Err(std::io::Error::new(std::io::ErrorKind::Other, "enough")))
+        .unwrap_err();
+    assert_eq!(err.to_string(), "command `yes` failed, enough");
+
+    let mut buf = Vec::new();
+    let status = cmd!("echo hello")
+        .pipe(cmd!("tr a-z A-Z"))
+        .run_stdout(|chunk| {
+            buf.extend_from_slice(chunk);
+            Ok(())
+        })
+        .unwrap();
+    assert!(status.success());
+    assert_eq!(buf, b"HELLO\n");
+}
+
+#[test]
+fn escape() {
+    let output = cmd!("echo \\hello\\ '\\world\\'").read().unwrap();
+    assert_eq!(output, r#"\hello\ \world\"#)
+}
+
+#[test]
+fn literal_escapes() {
+    // The literal's value is split, as without the `macros` feature.
+    assert_eq!(cmd!("echo a\tb '\x7c' \"c\"").get_args(), ["a", "b", "|", "\"c\""]);
+    assert_eq!(cmd!(r#"echo "a b" 'c d'e"#).get_args(), ["\"a", "b\"", "c de"]);
+    assert_eq!(cmd!(r"echo a\nb").get_args(), [r"a\nb"]);
+}
+
+#[test]
+fn stdin_redirection() {
+    let lines = "\
+foo
+baz
+bar
+";
+    let output = cmd!("sort").stdin(lines).read().unwrap();
+    assert_eq!(
+        output,
+        "\
+bar
+baz
+foo"
+    )
+}
+
+#[test]
+fn stdin_path() {
+    let file = mktemp_f().unwrap();
+    write_file(file.path(), "b\na\n").unwrap();
+    let output = cmd!("sort").stdin_path(file.path()).read().unwrap();
+    assert_eq!(output, "a\nb");
+
+    let err = cmd!("sort").stdin_path("missing.txt").read().unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "command `sort` failed, `missing.txt`: no such file or directory (os error 2)"
+    );
+}
+
+#[test]
+fn stdout_path() {
+    let dir = temp_dir().unwrap();
+    let out = dir.path().join("out.log");
+    let err = dir.path().join("err.log");
+
+    cmd!("echo hello").stdout_path(&out).run().unwrap();
+    cmd!("echo world").stdout_path_append(&out).run().unwrap();
+    assert_eq!(read_file(&out).unwrap(), "hello\nworld\n");
+    cmd!("echo truncated").stdout_path(&out).run().unwrap();
+    assert_eq!(read_file(&out).unwrap(), "truncated\n");
+
+    let stdout = cmd!("sh -c 'echo out; echo err >&2'").stderr_path(&err).read().unwrap();
+    assert_eq!(stdout, "out");
+    assert_eq!(read_file(&err).unwrap(), "err\n");
+
+    cmd!("echo b a").pipe(cmd!("tr ' ' '\\n'")).stdout_path(&out).run().unwrap();
+    assert_eq!(read_file(&out).unwrap(), "b\na\n");
+
+    let err = cmd!("echo hello").stdout_path("out.log").read().unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "command `echo hello` failed, stdout is redirected to `out.log`, can't read it"
+    );
+    assert!(!Path::new("out.log").exists());
+}
+
+#[test]
+fn stdin_reader() {
+    use std::io::Read;
+
+    // Generated lazily, never held in memory as a whole.
+    let input = std::io::repeat(b'x').take(64 * 1024 * 1024);
+    let output = cmd!("wc -c").stdin_reader(input).read().unwrap();
+    assert_eq!(output.trim(), (64 * 1024 * 1024).to_string());
+
+    let cmd = cmd!("cat").stdin_reader(&b"hello"[..]);
+    assert_eq!(cmd.clone().read().unwrap(), "hello");
+    assert_eq!(cmd.read().unwrap(), "");
+}
+
+#[test]
+fn test_pushd() {
+    let d1 = cwd().unwrap();
+    {
+        let _p = pushd("xshell-macros").unwrap();
+        let d2 = cwd().unwrap();
+        assert_eq!(d2, d1.join("xshell-macros"));
+        {
+            let _p = pushd("src").unwrap();
+            let d3 = cwd().unwrap();
+            assert_eq!(d3, d1.join("xshell-macros/src"));
+        }
+        let d4 = cwd().unwrap();
+        assert_eq!(d4, d1.join("xshell-macros"));
+    }
+    let d5 = cwd().unwrap();
+    assert_eq!(d5, d1);
+}
+
+#[test]
+fn pushd_removed_dir() {
+    let d1 = cwd().unwrap();
+    let dir = temp_dir().unwrap().into_path();
+    mkdir_p(dir.join("sub")).unwrap();
+    {
+        let _p = pushd(&dir).unwrap();
+        let dir = dir.canonicalize().unwrap();
+        let p = pushd("sub").unwrap();
+        assert_eq!(cwd().unwrap(), dir.join("sub"));
+        rm_rf(&dir).unwrap();
+        let err = p.into_result().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("`{}`: no such file or directory (os error 2)", dir.display())
+        );
+        assert!(cwd().is_err());
+    }
+    assert_eq!(cwd().unwrap(), d1);
+}
+
+#[test]
+fn test_cd() {
+    let d1 = cwd().unwrap();
+    {
+        // Hold the lock and restore the directory for the other tests.
+        let _p = pushd(".").unwrap();
+        cd("xshell-macros").unwrap();
+        assert_eq!(cwd().unwrap(), d1.join("xshell-macros"));
+        {
+            let _p = pushd("src").unwrap();
+            assert_eq!(cwd().unwrap(), d1.join("xshell-macros/src"));
+        }
+        assert_eq!(cwd().unwrap(), d1.join("xshell-macros"));
+        {
+            let _p = pushd("src").unwrap();
+            cd("..").unwrap();
+            assert_eq!(cwd().unwrap(), d1.join("xshell-macros"));
+        }
+        assert_eq!(cwd().unwrap(), d1.join("xshell-macros"));
+
+        let err = cd("nope").unwrap_err();
+        assert_eq!(err.to_string(), "`nope`: no such file or directory (os error 2)");
+    }
+    assert_eq!(cwd().unwrap(), d1);
+}
+
+#[test]
+fn pushd_parent_dir() {
+    let current = cwd().unwrap();
+    let dirname = current.file_name().unwrap();
+    let _d = pushd("..").unwrap();
+    let _d = pushd(dirname).unwrap();
+    assert_eq!(cwd().unwrap(), current);
+}
+
+#[cfg(feature = "extra-fs")]
+#[test]
+fn test_glob() {
+    with_temp_dir(|dir| {
+        let files =
+            ["a.rs", "b.txt", "sub/c.rs", "sub/deep/d.rs", "sub/deep/e.txt", ".hidden/f.rs"];
+        for file in files {
+            let path = dir.join(file);
+            mkdir_p(path.parent().unwrap())?;
+            write_file(path, "")?;
+        }
+        let _p = pushd(dir)?;
+
+        assert_eq!(glob("*.rs")?, [PathBuf::from("a.rs")]);
+        assert_eq!(glob("?.txt")?, [PathBuf::from("b.txt")]);
+        assert_eq!(
+            glob("**/*.rs")?,
+            ["a.rs", "sub/c.rs", "sub/deep/d.rs"].iter().map(PathBuf::from).collect::<Vec<_>>()
+        );
+        assert_eq!(glob("sub/**/*.txt")?, [PathBuf::from("sub/deep/e.txt")]);
+        assert_eq!(glob("**/deep")?, [PathBuf::from("sub/deep")]);
+        assert!(glob("*.nope")?.is_empty());
+        assert!(glob("nope/**")?.is_empty());
+        assert_eq!(glob(".*")?, [PathBuf::from(".hidden")]);
+        assert_eq!(glob(".hidden/*")?, [PathBuf::from(".hidden/f.rs")]);
+
+        let pattern = dir.join("sub/*.rs");
+        assert_eq!(glob(pattern.to_str().unwrap())?, [dir.join("sub/c.rs")]);
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[cfg(feature = "extra-fs")]
+#[test]
+fn test_walk_dir() {
+    with_temp_dir(|dir| {
+        mkdir_p(dir.join("b/d"))?;
+        write_file(dir.join("a.txt"), "")?;
+        write_file(dir.join("b/c.txt"), "")?;
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(dir, dir.join("b/d/loop")).unwrap();
+
+        let paths = walk_dir(dir)?;
+        let mut expected =
+            vec![dir.join("a.txt"), dir.join("b"), dir.join("b/c.txt"), dir.join("b/d")];
+        #[cfg(unix)]
+        expected.push(dir.join("b/d/loop"));
+        assert_eq!(paths, expected);
+
+        let lazy = walk_dir_iter(dir).collect::<xshell::Result<Vec<_>>>()?;
+        assert_eq!(lazy, expected);
+
+        let err = walk_dir(dir.join("nope")).unwrap_err();
+        assert!(
+            err.to_string().starts_with(&format!("`{}`", dir.join("nope").display())),
+            "{}",
+            err
+        );
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[test]
+fn test_touch() {
+    with_temp_dir(|dir| {
+        let stamp = dir.join("a/b/stamp");
+        touch(&stamp)?;
+        assert_eq!(read_file(&stamp)?, "");
+
+        write_file(&stamp, "hello")?;
+        cmd!("touch -t 200001010000 {stamp}").quiet().run()?;
+        let mtime = || std::fs::metadata(&stamp).unwrap().modified().unwrap();
+        let old = mtime();
+        touch(&stamp)?;
+        assert_eq!(read_file(&stamp)?, "hello");
+        assert!(mtime() > old);
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[cfg(all(unix, feature = "extra-fs"))]
+#[test]
+fn test_symlink() {
+    use xshell::symlink;
+
+    with_temp_dir(|dir| {
+        write_file(dir.join("file"), "hello")?;
+        symlink("file", dir.join("link"))?;
+        assert_eq!(read_file(dir.join("link"))?, "hello");
+        assert_eq!(std::fs::read_link(dir.join("link")).unwrap(), Path::new("file"));
+
+        let err = symlink("file", dir.join("link")).unwrap_err();
+        let prefix = format!("`file` -> `{}`: ", dir.join("link").display());
+        assert!(err.to_string().starts_with(&prefix), "{}", err);
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[cfg(feature = "extra-fs")]
+#[test]
+fn test_pushd_glob() {
+    let d1 = cwd().unwrap();
+    {
+        let _p = pushd_glob("cbench/base*").unwrap();
+        assert_eq!(cwd().unwrap(), d1.join("cbench/baseline"));
+    }
+    assert_eq!(cwd().unwrap(), d1);
+
+    let err = pushd_glob("cbench/nope*").err().unwrap();
+    assert_eq!(err.to_string(), "`cbench/nope*`: pattern matched no directories");
+
+    let err = pushd_glob("cbench/*").err().unwrap();
+    assert_eq!(
+        err.to_string(),
+        "`cbench/*`: pattern matched more than one directory: cbench/baseline, cbench/xshelled"
+    );
+}
+
+#[test]
+fn test_pushd_lock() {
+    let t1 = thread::spawn(|| {
+        let _p = pushd("cbench").unwrap();
+        sleep_ms(20);
+    });
+    sleep_ms(10);
+
+    let t2 = thread::spawn(|| {
+        let _p = pushd("cbench").unwrap();
+        sleep_ms(30);
+    });
+
+    t1.join().unwrap();
+    t2.join().unwrap();
+}
+
+#[cfg(feature = "extra-cmd")]
+#[test]
+fn batch() {
+    let res = Batch::new()
+        .cmd(cmd!("true"))
+        .cmd(cmd!("false"))
+        .cmd(cmd!("echo still running"))
+        .run_all_collect();
+    let res = res.iter().map(|(cmd, res)| (cmd.to_string(), res.is_ok())).collect::<Vec<_>>();
+    assert_eq!(
+        res,
+        vec![
+            ("true".to_string(), true),
+            ("false".to_string(), false),
+            ("echo still running".to_string(), true),
+        ]
+    );
+}
+
+#[test]
+fn current_dir() {
+    let d1 = cwd().unwrap();
+    let out = cmd!("pwd").current_dir("xshell-macros").read().unwrap();
+    assert_eq!(Path::new(&out), d1.join("xshell-macros"));
+    {
+        let _p = pushd("xshell-macros").unwrap();
+        let out = cmd!("pwd").current_dir("src").read().unwrap();
+        assert_eq!(Path::new(&out), d1.join("xshell-macros/src"));
+    }
+    assert_eq!(cmd!("echo hi").current_dir("src").to_string(), "echo hi");
+
+    let err = cmd!("ls").current_dir("nope").run().unwrap_err();
+    assert_eq!(err.to_string(), "command `ls` failed, working directory doesn't exist (in `nope`)");
+    let err = cmd!("nope").current_dir("src").run().unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "command `nope` failed: `nope` not found, is it installed and on PATH? (in `src`)"
+    );
+}
+
+#[cfg(feature = "extra-cmd")]
+#[test]
+fn test_run_in_dirs() {
+    let d1 = cwd().unwrap();
+    let dirs = ["xshell-macros".into(), "cbench".into()];
+    let res = run_in_dirs(&dirs, || cmd!("cat Cargo.toml"));
+    assert_eq!(res.len(), 2);
+    assert!(res[0].is_ok());
+    assert_eq!(
+        res[1].as_ref().unwrap_err().to_string(),
+        "command `cat Cargo.toml` failed, exit code: 1"
+    );
+    assert_eq!(cwd().unwrap(), d1);
+}
+
+#[cfg(unix)]
+#[test]
+fn handle_kill() {
+    let mut handle = cmd!("sleep 10").spawn().unwrap();
+    assert!(handle.id() > 0);
+    handle.kill().unwrap();
+    let err = handle.wait().unwrap_err();
+    assert_eq!(err.to_string(), "command `sleep 10` failed, killed by signal 9 (SIGKILL)");
+}
+
+#[cfg(unix)]
+#[test]
+fn shutdown_terminates() {
+    use std::os::unix::process::ExitStatusExt;
+
+    let handle = cmd!("sleep 10").spawn().unwrap();
+    let start = Instant::now();
+    let status = handle.shutdown(Duration::from_secs(5)).unwrap();
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert_eq!(status.signal(), Some(15));
+
+    let handle = Cmd::new("sh")
+        .arg("-c")
+        .arg("trap 'exit 0' TERM; while true; do sleep 0.1; done")
+        .spawn()
+        .unwrap();
+    sleep_ms(200);
+    let status = handle.shutdown(Duration::from_secs(5)).unwrap();
+    assert_eq!(status.code(), Some(0));
+}
+
+#[cfg(unix)]
+#[test]
+fn shutdown_kills_after_grace() {
+    use std::os::unix::process::ExitStatusExt;
+
+    let handle = Cmd::new("sh").arg("-c").arg("trap '' TERM; exec sleep 10").spawn().unwrap();
+    sleep_ms(200);
+    let start = Instant::now();
+    let status = handle.shutdown(Duration::from_millis(100)).unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(100));
+    assert_eq!(status.signal(), Some(9));
+}
+
+#[cfg(all(unix, feature = "libc"))]
+#[test]
+fn run_rusage() {
+    let busy_loop = "i=0; while [ $i -lt 20000 ]; do i=$((i+1)); done";
+    let usage = Cmd::new("sh").arg("-c").arg(busy_loop).run_rusage().unwrap();
+    assert!(usage.max_rss > 0);
+    assert!(usage.user_time + usage.sys_time > Duration::from_secs(0));
+
+    let err = cmd!("false | true").run_rusage().unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "command `false | true` failed, can't report the resource usage of a pipeline"
+    );
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn test_mmap_file() {
+    let map = unsafe { xshell::mmap_file("./LICENSE-MIT") }.unwrap();
+    assert_eq!(&map[..], read_file("./LICENSE-MIT").unwrap().as_bytes());
+}
+
+#[cfg(all(unix, feature = "ctrlc"))]
+#[test]
+fn ctrlc_kills_children() {
+    const CHILD: &str = "XSHELL_CTRLC_CHILD";
+    if let Some(pid_file) = std::env::var_os(CHILD) {
+        let pid_file = std::path::Path::new(&pid_file);
+        xshell::install_ctrlc_handler().unwrap();
+        // Finished children are dropped from the registry.
+        cmd!("true").run().unwrap();
+        cmd!("true").read().unwrap();
+        let script = format!("echo $$ > {}; exec sleep 30", pid_file.display());
+        let _ = cmd!("sh -c {script}").read();
+        return;
+    }
+
+    let dir = temp_dir().unwrap();
+    let pid_file = dir.path().join("pid");
+    let exe = std::env::current_exe().unwrap();
+    let mut child = std::process::Command::new(exe)
+        .args(["ctrlc_kills_children", "--exact"])
+        .env(CHILD, &pid_file)
+        .stdout(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+    let start = Instant::now();
+    let sleep_pid = loop {
+        match read_file(&pid_file) {
+            Ok(pid) if pid.ends_with('\n') => break pid.trim().to_string(),
+            _ => {
+                assert!(start.elapsed() < Duration::from_secs(10));
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+    };
+    let pid = child.id().to_string();
+    cmd!("kill -INT {pid}").run().unwrap();
+    assert_eq!(child.wait().unwrap().code(), Some(130));
+    assert!(cmd!("kill -0 {sleep_pid}").run().is_err());
+}
+
+#[cfg(feature = "mock")]
+#[test]
+fn mock_git() {
+    use xshell::{set_mock, MockOutput};
+
+    {
+        let _mock = set_mock(|cmd| {
+            if cmd.get_program() != "git" {
+                return None;
+            }
+            let args = cmd.get_args().iter().map(|it| it.to_str().unwrap()).collect::<Vec<_>>();
+            match args.as_slice() {
+                ["rev-parse", "HEAD"] => {
+                    Some(MockOutput { stdout: b"abc123\n".to_vec(), ..MockOutput::default() })
+                }
+                _ => Some(MockOutput { exit_code: 128, ..MockOutput::default() }),
+            }
+        });
+        assert_eq!(cmd!("git rev-parse HEAD").read().unwrap(), "abc123");
+        let err = cmd!("git push").run().unwrap_err();
+        assert_eq!(err.to_string(), "command `git push` failed, exit code: 128");
+        assert_eq!(cmd!("echo real").read().unwrap(), "real");
+        // Other threads run commands for real, without waiting for the mock.
+        let version = thread::spawn(|| cmd!("git version").quiet().read().unwrap());
+        assert!(version.join().unwrap().starts_with("git version"));
+    }
+    assert_ne!(cmd!("git version").read().unwrap(), "");
+}
+
+#[cfg(feature = "mock")]
+#[test]
+fn mock_runners() {
+    use xshell::{set_mock, MockOutput};
+
+    let _mock = set_mock(|cmd| {
+        if cmd.get_program() != "fake" {
+            return None;
+        }
+        // The handler can run commands itself.
+        let stdout = cmd!("printf 'a\\nb\\nc\\n'").read_bytes().unwrap();
+        Some(MockOutput { stdout, ..MockOutput::default() })
+    });
+    assert_eq!(cmd!("fake").read_tail(2).unwrap(), ["b", "c"]);
+    assert_eq!(cmd!("fake").read_truncated(3).unwrap(), ("a\nb".to_string(), true));
+    assert_eq!(cmd!("fake").pipe(cmd!("sort -r")).read().unwrap(), "a\nb\nc");
+
+    let mut stdout = Vec::new();
+    let status = cmd!("fake").run_stdout(|chunk| std::io::Write::write_all(&mut stdout, chunk));
+    assert!(status.unwrap().success());
+    assert_eq!(stdout, b"a\nb\nc\n");
+
+    let mut lines = Vec::new();
+    cmd!("fake").run_split_streaming(|line| lines.push(line.to_string()), |_line| ()).unwrap();
+    assert_eq!(lines, ["a", "b", "c"]);
+}
+
+#[cfg(feature = "toml")]
+#[test]
+fn toml_round_trip() {
+    use std::collections::BTreeMap;
+
+    let dir = temp_dir().unwrap();
+    let path = dir.path().join("round-trip.toml");
+    let mut value = BTreeMap::new();
+    value.insert("name".to_string(), vec![1, 2, 3]);
+    xshell::write_toml(&path, &value).unwrap();
+    let actual: BTreeMap<String, Vec<u32>> = xshell::read_toml(&path).unwrap();
+    assert_eq!(actual, value);
+
+    write_file(&path, "name = ").unwrap();
+    let err = xshell::read_toml::<BTreeMap<String, u32>>(&path).unwrap_err();
+    assert!(err.to_string().starts_with(&format!("`{}`: ", path.display())), "{}", err);
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn json_round_trip() {
+    use std::collections::BTreeMap;
+
+    let dir = temp_dir().unwrap();
+    let path = dir.path().join("round-trip.json");
+    let mut value = BTreeMap::new();
+    value.insert("name".to_string(), vec![1, 2, 3]);
+    xshell::write_json(&path, &value).unwrap();
+    let actual: BTreeMap<String, Vec<u32>> = xshell::read_json(&path).unwrap();
+    assert_eq!(actual, value);
+
+    write_file(&path, "{").unwrap();
+    let err = xshell::read_json::<BTreeMap<String, u32>>(&path).unwrap_err();
+    assert!(err.to_string().starts_with(&format!("`{}`: ", path.display())), "{}", err);
+}
+
+#[cfg(unix)]
+#[test]
+fn keep_fd() {
+    use std::{fs::File, io::Read, os::unix::io::FromRawFd};
+
+    extern "C" {
+        fn pipe(fds: *mut i32) -> i32;
+        fn fcntl(fd: i32, cmd: i32, ...) -> i32;
+    }
+    const F_SETFD: i32 = 2;
+    const FD_CLOEXEC: i32 = 1;
+
+    let mut fds = [0; 2];
+    let (mut reader, writer) = unsafe {
+        assert_eq!(pipe(fds.as_mut_ptr()), 0);
+        // Mimic std, which opens everything with `FD_CLOEXEC`.
+        assert_ne!(fcntl(fds[0], F_SETFD, FD_CLOEXEC), -1);
+        assert_ne!(fcntl(fds[1], F_SETFD, FD_CLOEXEC), -1);
+        (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1]))
+    };
+
+    let script = format!("echo hello > /dev/fd/{}", fds[1]);
+    Cmd::new("sh").arg("-c").arg(script).keep_fd(fds[1]).run().unwrap();
+    drop(writer);
+
+    let mut buf = String::new();
+    reader.read_to_string(&mut buf).unwrap();
+    assert_eq!(buf, "hello\n");
+}
+
+#[cfg(feature = "extra-fs")]
+#[test]
+fn test_cp_if_newer() {
+    let dir = std::env::temp_dir().join("xshell-test-cp-if-newer");
+    rm_rf(&dir).unwrap();
+    mkdir_p(&dir).unwrap();
+    let (src, dst) = (dir.join("src"), dir.join("dst"));
+    write_file(&src, "new").unwrap();
+
+    assert!(cp_if_newer(&src, &dst).unwrap());
+    assert_eq!(read_file(&dst).unwrap(), "new");
+
+    write_file(&dst, "old").unwrap();
+    cmd!("touch -t 200001010000 {dst}").run().unwrap();
+    assert!(cp_if_newer(&src, &dst).unwrap());
+    assert_eq!(read_file(&dst).unwrap(), "new");
+
+    write_file(&dst, "newer").unwrap();
+    cmd!("touch -t 200001010000 {src}").run().unwrap();
+    assert!(!cp_if_newer(&src, &dst).unwrap());
+    assert_eq!(read_file(&dst).unwrap(), "newer");
+    rm_rf(&dir).unwrap();
+}
+
+#[cfg(feature = "extra-fs")]
+#[test]
+fn test_cp_if_changed() {
+    with_temp_dir(|dir| {
+        let (src, dst) = (dir.join("src"), dir.join("dst"));
+        write_file(&src, "new")?;
+
+        assert!(cp_if_changed(&src, &dst)?);
+        assert_eq!(read_file(&dst)?, "new");
+
+        cmd!("touch -t 200001010000 {dst}").quiet().run()?;
+        let mtime = || std::fs::metadata(&dst).unwrap().modified().unwrap();
+        let old = mtime();
+        assert!(!cp_if_changed(&src, &dst)?);
+        assert_eq!(mtime(), old);
+
+        write_file(&src, "old")?;
+        assert!(cp_if_changed(&src, &dst)?);
+        assert_eq!(read_file(&dst)?, "old");
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[test]
+fn test_target_bin() {
+    let dir = std::env::temp_dir().join("xshell-test-target-bin");
+    rm_rf(&dir).unwrap();
+    let exe = format!("app{}", std::env::consts::EXE_SUFFIX);
+    mkdir_p(dir.join("target/release")).unwrap();
+    write_file(dir.join("target/release").join(&exe), "").unwrap();
+    mkdir_p(dir.join("custom/debug")).unwrap();
+    write_file(dir.join("custom/debug").join(&exe), "").unwrap();
+
+    let _p = pushd(&dir).unwrap();
+    if std::env::var_os("CARGO_TARGET_DIR").is_none() {
+        let path = target_bin("app", true).unwrap();
+        assert_eq!(path, Path::new("target/release").join(&exe));
+        let err = target_bin("app", false).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("`{}`: binary not found", Path::new("target/debug").join(&exe).display())
+        );
+    }
+    {
+        let _e = pushenv("CARGO_TARGET_DIR", dir.join("custom"));
+        assert_eq!(target_bin("app", false).unwrap(), dir.join("custom/debug").join(&exe));
+        assert!(target_bin("app", true).is_err());
+    }
+    drop(_p);
+    rm_rf(&dir).unwrap();
+}
+
+#[cfg(feature = "extra-fs")]
+#[test]
+fn test_hard_link() {
+    let dir = std::env::temp_dir().join("xshell-test-hard-link");
+    rm_rf(&dir).unwrap();
+    mkdir_p(&dir).unwrap();
+    write_file(dir.join("src"), "hello").unwrap();
+    hard_link(dir.join("src"), dir.join("dst")).unwrap();
+    assert_eq!(read_file(dir.join("dst")).unwrap(), "hello");
+
+    let err = hard_link(dir.join("missing"), dir.join("dst2")).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        format!(
+            "`{}` -> `{}`: no such file or directory (os error 2)",
+            dir.join("missing").display(),
+            dir.join("dst2").display()
+        )
+    );
+    rm_rf(&dir).unwrap();
+}
+
+#[test]
+fn test_read_file_bytes() {
+    let file = mktemp_f().unwrap();
+    let bytes = [0u8, 0xff, b'\n', 0xfe];
+    write_file(file.path(), bytes).unwrap();
+    assert_eq!(read_file_bytes(file.path()).unwrap(), bytes);
+    assert!(read_file(file.path()).is_err());
+
+    let err = read_file_bytes("missing.bin").unwrap_err();
+    assert_eq!(err.to_string(), "`missing.bin`: no such file or directory (os error 2)");
+}
+
+#[test]
+fn test_rm_rf() {
+    with_temp_dir(|dir| {
+        let missing = dir.join("missing");
+        rm_rf(&missing)?;
+        let err = rm_rf_strict(&missing).unwrap_err();
+        assert_eq!(err.io_kind(), Some(std::io::ErrorKind::NotFound));
+        assert!(err.to_string().starts_with(&format!("`{}`", missing.display())), "{}", err);
+
+        write_file(dir.join("file"), "")?;
+        rm_rf_strict(dir.join("file"))?;
+        mkdir_p(dir.join("tree/sub"))?;
+        write_file(dir.join("tree/sub/file"), "")?;
+        rm_rf(dir.join("tree"))?;
+        assert!(!dir.join("tree").exists());
+
+        #[cfg(unix)]
+        {
+            mkdir_p(dir.join("target"))?;
+            write_file(dir.join("target/keep"), "")?;
+            std::os::unix::fs::symlink(dir.join("target"), dir.join("link")).unwrap();
+            std::os::unix::fs::symlink(dir.join("missing"), dir.join("dangling")).unwrap();
+            rm_rf_strict(dir.join("link"))?;
+            rm_rf_strict(dir.join("dangling"))?;
+            assert!(std::fs::symlink_metadata(dir.join("link")).is_err());
+            assert!(std::fs::symlink_metadata(dir.join("dangling")).is_err());
+            assert!(dir.join("target/keep").exists());
+        }
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[test]
+fn test_mv() {
+    let dir = std::env::temp_dir().join("xshell-test-mv");
+    rm_rf(&dir).unwrap();
+    mkdir_p(&dir).unwrap();
+    write_file(dir.join("src"), "hello").unwrap();
+    mv(dir.join("src"), dir.join("dst")).unwrap();
+    assert!(!dir.join("src").exists());
+    assert_eq!(read_file(dir.join("dst")).unwrap(), "hello");
+
+    let err = mv(dir.join("missing"), dir.join("dst2")).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        format!(
+            "`{}` -> `{}`: no such file or directory (os error 2)",
+            dir.join("missing").display(),
+            dir.join("dst2").display()
+        )
+    );
+
+    // A different filesystem, at least on most Linux systems.
+    let shm = Path::new("/dev/shm");
+    if shm.is_dir() {
+        let other = shm.join(format!("xshell-test-mv-{}", std::process::id()));
+        mv(dir.join("dst"), &other).unwrap();
+        assert!(!dir.join("dst").exists());
+        assert_eq!(read_file(&other).unwrap(), "hello");
+        rm_rf(&other).unwrap();
+
+        mkdir_p(dir.join("sub")).unwrap();
+        write_file(dir.join("sub/file"), "hello").unwrap();
+        mv(dir.join("sub"), &other).unwrap();
+        assert!(!dir.join("sub").exists());
+        assert_eq!(read_file(other.join("file")).unwrap(), "hello");
+        rm_rf(&other).unwrap();
+    }
+    rm_rf(&dir).unwrap();
+}
+
+#[test]
+fn test_cp_r() {
+    let dir = std::env::temp_dir().join("xshell-test-cp-r");
+    rm_rf(&dir).unwrap();
+    mkdir_p(dir.join("src/sub")).unwrap();
+    write_file(dir.join("src/a"), "a").unwrap();
+    write_file(dir.join("src/sub/b"), "b").unwrap();
+    #[cfg(unix)]
+    std::os::unix::fs::symlink("..", dir.join("src/sub/up")).unwrap();
+
+    cp_r(dir.join("src"), dir.join("dst")).unwrap();
+    assert_eq!(read_file(dir.join("dst/a")).unwrap(), "a");
+    assert_eq!(read_file(dir.join("dst/sub/b")).unwrap(), "b");
+    #[cfg(unix)]
+    assert_eq!(std::fs::read_link(dir.join("dst/sub/up")).unwrap(), Path::new(".."));
+
+    // Into an existing directory.
+    cp_r(dir.join("src"), dir.join("dst")).unwrap();
+    assert_eq!(read_file(dir.join("dst/src/sub/b")).unwrap(), "b");
+
+    let err = cp_r(dir.join("src"), dir.join("src/sub")).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        format!(
+            "`{}` -> `{}`: can't copy into itself",
+            dir.join("src").display(),
+            dir.join("src/sub/src").display()
+        )
+    );
+    rm_rf(&dir).unwrap();
+}
+
+#[cfg(windows)]
+#[test]
+fn path_too_long() {
+    let path = std::env::temp_dir().join("x".repeat(300)).join("file");
+    let err = match write_file(&path, "hello") {
+        Ok(()) => return, // long paths are enabled on this machine
+        Err(err) => err,
+    };
+    assert!(err.to_string().contains("longer than MAX_PATH"), "{}", err);
+}
+
+#[cfg(feature = "extra-cmd")]
+#[test]
+fn run_capture_on_error() {
+    cmd!("echo hello").run_capture_on_error().unwrap();
+
+    let script = "seq 10000; sleep 0.1; echo oops >&2; exit 1";
+    let err = cmd!("sh -c {script}").run_capture_on_error().unwrap_err();
+    let msg = err.to_string();
+    let (head, tail) = msg.split_once(", output tail:\n").unwrap();
+    assert_eq!(head, format!("command `sh -c \"{}\"` failed, exit code: 1", script));
+    assert!(tail.ends_with("9999\n10000\noops\n"), "{}", tail);
+    assert!(!tail.starts_with("1\n"));
+
+    let err = cmd!("false | true").run_capture_on_error().unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "command `false | true` failed, can't capture stderr of a pipeline"
+    );
+}
+
+#[cfg(feature = "extra-cmd")]
+#[test]
+fn run_map_err() {
+    #[derive(Debug)]
+    enum MyError {
+        Cmd(String),
+    }
+
+    cmd!("true").run_map_err(|err| MyError::Cmd(err.to_string())).unwrap();
+    let err = cmd!("false").run_map_err(|err| MyError::Cmd(err.to_string())).unwrap_err();
+    match err {
+        MyError::Cmd(msg) => assert_eq!(msg, "command `false` failed, exit code: 1"),
+    }
+}
+
+#[cfg(feature = "extra-cmd")]
+#[test]
+fn run_with_heartbeat() {
+    cmd!("sleep 0.3").run_with_heartbeat(Duration::from_millis(50)).unwrap();
+    let err = cmd!("false").run_with_heartbeat(Duration::from_millis(50)).unwrap_err();
+    assert_eq!(err.to_string(), "command `false` failed, exit code: 1");
+    let err = cmd!("false | true").run_with_heartbeat(Duration::from_millis(50)).unwrap_err();
+    assert_eq!(err.to_string(), "pipeline failed at stage 1 (`false`): exit code 1");
+}
+
+#[test]
+fn timeout() {
+    let start = Instant::now();
+    let err = cmd!("sleep 10").timeout(Duration::from_millis(100)).read().unwrap_err();
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert!(err.to_string().starts_with("command `sleep 10` timed out after 1"));
+
+    let err = cmd!("sleep 10").timeout(Duration::from_millis(100)).run().unwrap_err();
+    assert!(err.to_string().starts_with("command `sleep 10` timed out after"));
+
+    let output = cmd!("echo hello").timeout(Duration::from_secs(10)).read().unwrap();
+    assert_eq!(output, "hello");
+
+    let timed_out = |res: xshell::Result<()>| {
+        let err = res.unwrap_err().to_string();
+        assert!(err.contains("` timed out after"), "{}", err);
+    };
+    let start = Instant::now();
+    let timeout = Duration::from_millis(100);
+    timed_out(cmd!("sleep 10 | cat").timeout(timeout).run());
+    timed_out(cmd!("sleep 10 | cat").timeout(timeout).read().map(drop));
+    timed_out(cmd!("sleep 10").log_prefix("[sleep] ").timeout(timeout).run());
+    #[cfg(feature = "extra-cmd")]
+    {
+        timed_out(cmd!("sleep 10").timeout(timeout).read_tail(1).map(drop));
+        timed_out(cmd!("sleep 10").timeout(timeout).read_truncated(1).map(drop));
+        timed_out(cmd!("sleep 10").timeout(timeout).run_capture_on_error());
+    }
+    #[cfg(all(unix, feature = "libc"))]
+    timed_out(cmd!("sleep 10").timeout(timeout).run_rusage().map(drop));
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn test_mktemp_f() {
+    use std::io::Write;
+
+    let tmp = mktemp_f().unwrap();
+    let path = tmp.path().to_path_buf();
+    tmp.file().write_all(b"hello").unwrap();
+    assert_eq!(cmd!("cat {path}").read().unwrap(), "hello");
+    assert_ne!(mktemp_f().unwrap().path(), path);
+    drop(tmp);
+    assert!(!path.exists());
+}
+
+#[test]
+fn test_with_temp_dir() {
+    let mut path = PathBuf::new();
+    let text = with_temp_dir(|dir| {
+        path = dir.to_path_buf();
+        write_file(dir.join("file"), "hello")?;
+        read_file(dir.join("file"))
+    })
+    .unwrap();
+    assert_eq!(text, "hello");
+    assert!(!path.exists());
+
+    let err = with_temp_dir(|dir| {
+        path = dir.to_path_buf();
+        read_file(dir.join("missing"))
+    })
+    .unwrap_err();
+    assert!(err.to_string().contains("no such file"));
+    assert!(!path.exists());
+
+    let res = std::panic::catch_unwind(|| {
+        with_temp_dir::<()>(|dir| {
+            write_file(dir.join("file"), "hello").unwrap();
+            panic!("{}", dir.display())
+        })
+    });
+    let msg = res.unwrap_err().downcast::<String>().unwrap();
+    assert!(!Path::new(&*msg).exists());
+}
+
+#[test]
+fn test_temp_dir() {
+    let dir = temp_dir().unwrap();
+    let path = dir.path().to_path_buf();
+    assert!(path.is_dir());
+    write_file(path.join("file"), "hello").unwrap();
+    drop(dir);
+    assert!(!path.exists());
+
+    let path = temp_dir().unwrap().into_path();
+    assert!(path.is_dir());
+    rm_rf(&path).unwrap();
+}
+
+#[test]
+fn test_normalize() {
+    let check = |path: &str, expected: &str| assert_eq!(normalize(path), Path::new(expected));
+    check("a/./b/../c", "a/c");
+    check("./a/.", "a");
+    check("a//b///c/", "a/b/c");
+    check("a/..", ".");
+    check("../a/../../b", "../../b");
+    check("/a/../..", "/");
+    check("", ".");
+}
+
+#[cfg(feature = "extra-fs")]
+#[test]
+fn test_read_dir_entries() {
+    with_temp_dir(|dir| {
+        for name in ["c", "a", "b"] {
+            write_file(dir.join(name), "")?;
+        }
+        mkdir_p(dir.join("d"))?;
+        assert_eq!(read_dir(dir)?, ["a", "b", "c", "d"].map(|it| dir.join(it)));
+
+        let entries = read_dir_entries(dir)?;
+        let names = entries.iter().map(|it| it.file_name().to_str().unwrap()).collect::<Vec<_>>();
+        assert_eq!(names, ["a", "b", "c", "d"]);
+        assert_eq!(entries[0].path(), dir.join("a"));
+        assert!(entries[0].is_file() && !entries[0].is_dir());
+        assert!(entries[3].is_dir() && !entries[3].is_symlink());
+
+        let err = read_dir_entries(dir.join("a")).unwrap_err();
+        assert!(err.to_string().ends_with("`: not a directory"), "{}", err);
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[test]
+fn read_dir_of_file() {
+    let err = read_dir("Cargo.toml").unwrap_err();
+    assert_eq!(err.to_string(), "`Cargo.toml`: not a directory");
+}
+
+#[test]
+fn read_dir_missing() {
+    let err = read_dir("no-such-dir").unwrap_err();
+    assert_eq!(err.to_string(), "`no-such-dir`: no such file or directory (os error 2)");
+}
+
+#[cfg(all(unix, feature = "extra-fs"))]
+#[test]
+fn test_which() {
+    use std::os::unix::fs::PermissionsExt;
+    use xshell::which;
+
+    let sh = which("sh").unwrap();
+    assert!(sh.is_absolute() && sh.ends_with("sh"), "{}", sh.display());
+    assert_eq!(which("xshell-no-such-program"), None);
+
+    with_temp_dir(|dir| {
+        write_file(dir.join("tool"), "#!/bin/sh\n")?;
+        write_file(dir.join("data"), "")?;
+        std::fs::set_permissions(dir.join("tool"), std::fs::Permissions::from_mode(0o755)).unwrap();
+        let _e = pushenv("PATH", join_path_dirs(&[dir.to_path_buf()])?);
+        assert_eq!(which("tool"), Some(dir.join("tool")));
+        assert_eq!(which("data"), None);
+
+        let _p = pushd(dir)?;
+        assert_eq!(which("./tool"), Some(dir.join("./tool")));
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[test]
+fn test_num_cpus() {
+    assert!(num_cpus() >= 1);
+}
+
+#[test]
+fn unlocked_cmd() {
+    let (tx, rx) = std::sync::mpsc::channel();
+    let t = thread::spawn(move || {
+        let _p = pushd("cbench").unwrap();
+        tx.send(()).unwrap();
+        sleep_ms(300);
+    });
+    rx.recv().unwrap();
+    let start = Instant::now();
+    cmd!("true").unlocked().run().unwrap();
+    assert!(start.elapsed() < Duration::from_millis(200));
+    t.join().unwrap();
+}
+
+#[test]
+fn cmd_while_joined_inside_pushd() {
+    let _p = pushd("cbench").unwrap();
+    let t = thread::spawn(|| cmd!("echo hi").quiet().read().unwrap());
+    assert_eq!(t.join().unwrap(), "hi");
+}
+
+const VAR: &str = "SPICA";
+
+#[test]
+fn test_pushenv() {
+    let e1 = std::env::var_os(VAR);
+    {
+        let _e = pushenv(VAR, "1");
+        let e2 = std::env::var_os(VAR);
+        assert_eq!(e2, Some("1".into()));
+        {
+            let _e = pushenv(VAR, "2");
+            let e3 = std::env::var_os(VAR);
+            assert_eq!(e3, Some("2".into()));
+        }
+        let e4 = std::env::var_os(VAR);
+        assert_eq!(e4, e2);
+    }
+    let e5 = std::env::var_os(VAR);
+    assert_eq!(e5, e1);
+}
+
+#[test]
+fn test_pushenv_remove() {
+    const VAR: &str = "XSHELL_TEST_PUSHENV_REMOVE";
+    {
+        let _e = pushenv_remove(VAR);
+        assert_eq!(std::env::var_os(VAR), None);
+    }
+    assert_eq!(std::env::var_os(VAR), None);
+
+    let _e = pushenv(VAR, "1");
+    {
+        let _e = pushenv_remove(VAR);
+        assert_eq!(std::env::var_os(VAR), None);
+        let out = cmd!("sh -c 'echo ${XSHELL_TEST_PUSHENV_REMOVE-unset}'").read().unwrap();
+        assert_eq!(out, "unset");
+    }
+    assert_eq!(std::env::var_os(VAR), Some("1".into()));
 }
 
 #[test]
@@ -0,0 +1,25 @@
+//! Runs without the `macros` feature too, where `cmd!` only takes plain
+//! command lines.
+use xshell::{cmd, Cmd};
+
+#[test]
+fn plain_cmd() {
+    let output = cmd!("echo hello").read().unwrap();
+    assert_eq!(output, "hello");
+
+    let cmd = cmd!(r#"echo 'a b' "c d" e\f '{}|' a|b"#);
+    assert_eq!(cmd.get_args(), ["a b", "\"c", "d\"", r"e\f", "{}|", "a|b"]);
+    let cmd = cmd!("echo 'a'b' c'");
+    assert_eq!(cmd.get_args(), ["ab c"]);
+}
+
+#[test]
+fn interpolate_with_builder() {
+    let name = "world";
+    let words = ["a", "b"];
+    let output = cmd!("echo hello").arg(name).args(&words).read().unwrap();
+    assert_eq!(output, "hello world a b");
+
+    let output = cmd!("echo hello").pipe(Cmd::new("tr").args(["a-z", "A-Z"])).read().unwrap();
+    assert_eq!(output, "HELLO");
+}
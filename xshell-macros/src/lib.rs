@@ -1,8 +1,6 @@
 extern crate proc_macro;
 
-use std::iter;
-
-use proc_macro::{Group, Span, TokenStream, TokenTree};
+use proc_macro::{Delimiter, Group, Literal, Span, TokenStream, TokenTree};
 
 #[doc(hidden)]
 #[proc_macro]
@@ -15,21 +13,38 @@ pub fn __cmd(macro_arg: TokenStream) -> TokenStream {
         (cmd, literal)
     };
 
-    let literal_text = literal.to_string();
-    let mut args = shell_lex(literal_text.as_str(), literal.span());
+    // Tokenize the value rather than the source text, so that escapes and raw
+    // strings split the same way as the declarative `cmd!` without `macros`.
+    let literal_text = literal_value(&literal.to_string());
+    let mut stages = split_pipeline(tokenize(literal_text.as_str())).into_iter();
 
-    let mut res = TokenStream::new();
+    // `a | b | c` is lowered to `Cmd::new(a).pipe(Cmd::new(b)).pipe(Cmd::new(c))`.
+    let mut res = stage(&cmd, stages.next().unwrap(), literal.span());
+    for tokens in stages {
+        extend(&mut res, parse_ts(".pipe"));
+        let next = stage(&cmd, tokens, literal.span());
+        extend(&mut res, parenthesize(next));
+    }
+    res
+}
+
+fn stage(cmd: &TokenTree, tokens: Vec<Token<'_>>, call_site: Span) -> TokenStream {
+    let mut tokens = tokens.into_iter();
+
+    let mut res = TokenStream::from(cmd.clone());
 
     {
-        let (_joined_to_prev, splat, program) = args.next().expect("command line is empty!");
+        let program = tokens.next().expect("command line is empty!");
+        let (splat, program) = shell_lex(&program, call_site);
         assert!(!splat);
-        res.extend(Some(cmd));
-        res.extend(parse_ts("::new"));
-        res.extend(program);
+        extend(&mut res, parse_ts("::new"));
+        extend(&mut res, program);
     }
 
     let mut prev_spat = false;
-    for (joined_to_prev, splat, arg) in args {
+    for token in tokens {
+        let joined_to_prev = token.joined_to_prev;
+        let (splat, arg) = shell_lex(&token, call_site);
         assert!(!(joined_to_prev && splat));
         if prev_spat && joined_to_prev {
             panic!("can't splat and concat simultaneously")
@@ -43,53 +58,88 @@ pub fn __cmd(macro_arg: TokenStream) -> TokenStream {
             (true, true) => panic!("can't splat and concat simultaneously"),
         };
 
-        res.extend(parse_ts(method));
-        res.extend(arg);
+        extend(&mut res, parse_ts(method));
+        extend(&mut res, arg);
     }
 
     res
 }
 
-fn shell_lex(cmd: &str, call_site: Span) -> impl Iterator<Item = (bool, bool, TokenStream)> + '_ {
+/// Splits tokens into pipeline stages, on unquoted `|` words.
+fn split_pipeline(tokens: Vec<Token<'_>>) -> Vec<Vec<Token<'_>>> {
+    let mut stages = vec![Vec::new()];
+    for token in tokens {
+        if matches!(token.kind, TokenKind::Word) && token.text == "|" {
+            stages.push(Vec::new());
+        } else {
+            stages.last_mut().unwrap().push(token);
+        }
+    }
+    stages
+}
+
+/// Lexes a token into an argument, or into a slice of arguments if `splat`.
+fn shell_lex(token: &Token<'_>, call_site: Span) -> (bool, TokenStream) {
     fn trim_decorations(s: &str) -> &str {
         &s[1..s.len() - 1]
     }
 
-    tokenize(cmd).map(move |token| {
-        let mut splat = false;
-        let ts = match token.kind {
-            TokenKind::Word => parse_ts(&format!("(\"{}\")", token.text)),
-            TokenKind::String => parse_ts(&format!("(\"{}\")", trim_decorations(token.text))),
-            TokenKind::Interpolation { splat: s } => {
-                splat = s;
-                let text = trim_decorations(token.text);
-                let text = &text[..text.len() - (if splat { "...".len() } else { 0 })];
-                assert!(
-                    text.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'),
-                    "can only interpolate variables"
-                );
-                let ts = if splat { format!("({})", text) } else { format!("(&({}))", text) };
-                respan(parse_ts(&ts), call_site)
-            }
-        };
-        (token.joined_to_prev, splat, ts)
-    })
+    let mut splat = false;
+    let ts = match token.kind {
+        TokenKind::Word => string_arg(token.text),
+        TokenKind::String => string_arg(trim_decorations(token.text)),
+        TokenKind::Interpolation { splat: s } => {
+            splat = s;
+            let text = trim_decorations(token.text);
+            let text = &text[..text.len() - (if splat { "...".len() } else { 0 })];
+            // `{cond:--flag}` expands to `--flag` if `cond` is true, and to
+            // nothing otherwise. `{val:spec}` formats `val` with `spec`,
+            // like `format!`.
+            let (text, suffix) = match find_colon(text) {
+                Some(idx) => (&text[..idx], Some(&text[idx + 1..])),
+                None => (text, None),
+            };
+            let ts = match suffix {
+                Some(spec) if !spec.starts_with('-') => {
+                    assert!(!splat, "can't use a format spec with splat");
+                    ["(::std::format!(\"{:", spec, "}\", ", text, "))"].concat()
+                }
+                Some(flag) => {
+                    assert!(
+                        !flag.bytes().any(|b| b.is_ascii_whitespace()),
+                        "flag must start with `-` and can't contain whitespace"
+                    );
+                    assert!(!token.joined_to_prev, "can't concat a flag");
+                    splat = true;
+                    ["(if ", text, " { &[\"", flag, "\"][..] } else { &[][..] })"].concat()
+                }
+                None if splat => ["(", text, ")"].concat(),
+                // Anything `AsRef<OsStr>` is passed as is, other values,
+                // like numbers, are formatted with `Display`.
+                None => ["((&__ARG(&(", text, "))).__into_arg())"].concat(),
+            };
+            respan(parse_ts(&ts), call_site)
+        }
+    };
+    (splat, ts)
 }
 
-fn tokenize(cmd: &str) -> impl Iterator<Item = Token<'_>> + '_ {
-    let mut cmd = cmd.trim_matches('"');
-    iter::from_fn(move || {
+/// Splits the command line into words, quoted strings and interpolations.
+/// The declarative `cmd!` mirrors this in `xshell::__private::split`, keep
+/// the two in sync.
+fn tokenize(mut cmd: &str) -> Vec<Token<'_>> {
+    let mut res = Vec::new();
+    loop {
         let old_len = cmd.len();
         cmd = cmd.trim_start();
         let joined_to_prev = old_len == cmd.len();
         if cmd.is_empty() {
-            return None;
+            return res;
         }
         let (len, kind) = next_token(cmd);
-        let token = Token { joined_to_prev, text: &cmd[..len], kind };
+        res.push(Token { joined_to_prev, text: &cmd[..len], kind });
         cmd = &cmd[len..];
-        Some(token)
-    })
+    }
 }
 
 #[derive(Debug)]
@@ -106,38 +156,149 @@ enum TokenKind {
 }
 
 fn next_token(s: &str) -> (usize, TokenKind) {
-    if s.starts_with('{') {
-        let len = s.find('}').unwrap() + 1;
-        let splat = s[..len].ends_with("...}");
+    let bytes = s.as_bytes();
+    if bytes[0] == b'{' {
+        let mut depth = 0;
+        let mut len = 0;
+        loop {
+            match bytes.get(len).expect("unbalanced `{`") {
+                b'{' => depth += 1,
+                b'}' => depth -= 1,
+                _ => (),
+            }
+            len += 1;
+            if depth == 0 {
+                break;
+            }
+        }
+        let splat = bytes[..len].ends_with(b"...}");
         return (len, TokenKind::Interpolation { splat });
     }
-    if s.starts_with('\'') {
-        let len = s[1..].find('\'').unwrap() + 2;
+    if bytes[0] == b'\'' {
+        let len = position(&bytes[1..], |b| b == b'\'').unwrap() + 2;
         return (len, TokenKind::String);
     }
     let len =
-        s.find(|it: char| it.is_ascii_whitespace() || it == '\'' || it == '{').unwrap_or(s.len());
+        position(bytes, |b| b.is_ascii_whitespace() || b == b'\'' || b == b'{').unwrap_or(s.len());
     (len, TokenKind::Word)
 }
 
-fn respan(ts: TokenStream, span: Span) -> TokenStream {
-    let mut res = TokenStream::new();
-    for tt in ts {
-        let tt = match tt {
-            TokenTree::Ident(mut ident) => {
-                ident.set_span(ident.span().resolved_at(span));
-                TokenTree::Ident(ident)
+fn position(bytes: &[u8], pred: fn(u8) -> bool) -> Option<usize> {
+    bytes.iter().position(|&b| pred(b))
+}
+
+/// Finds the `:` separating an interpolated expression from its suffix,
+/// skipping over paths like `a::b` and nested brackets.
+fn find_colon(text: &str) -> Option<usize> {
+    let bytes = text.as_bytes();
+    let mut depth = 0;
+    for i in 0..bytes.len() {
+        match bytes[i] {
+            b'(' | b'[' | b'{' => depth += 1,
+            b')' | b']' | b'}' => depth -= 1,
+            b':' if depth == 0 => {
+                let path = (i > 0 && bytes[i - 1] == b':') || bytes.get(i + 1) == Some(&b':');
+                if !path {
+                    return Some(i);
+                }
+            }
+            _ => (),
+        }
+    }
+    None
+}
+
+/// Returns the value of a string literal, given its source text. Raw strings
+/// are taken as is, escapes in ordinary strings are undone, which matters
+/// for expressions like `{name.trim_end_matches(\"/\")}`.
+fn literal_value(literal: &str) -> String {
+    let bytes = literal.as_bytes();
+    if bytes[0] == b'r' {
+        let hashes = bytes[1..].iter().take_while(|&&b| b == b'#').count();
+        return literal[hashes + 2..literal.len() - hashes - 1].to_string();
+    }
+    assert!(
+        bytes.len() >= 2 && bytes[0] == b'"' && bytes[bytes.len() - 1] == b'"',
+        "`cmd!` expects a string literal"
+    );
+    let text = &bytes[1..bytes.len() - 1];
+    let mut res = Vec::with_capacity(text.len());
+    let mut i = 0;
+    while i < text.len() {
+        let b = text[i];
+        i += 1;
+        if b != b'\\' {
+            res.push(b);
+            continue;
+        }
+        let b = text[i];
+        i += 1;
+        match b {
+            b'n' => res.push(b'\n'),
+            b'r' => res.push(b'\r'),
+            b't' => res.push(b'\t'),
+            b'0' => res.push(b'\0'),
+            b'x' => {
+                res.push(hex(&text[i..i + 2]) as u8);
+                i += 2;
             }
-            TokenTree::Group(group) => {
-                TokenTree::Group(Group::new(group.delimiter(), respan(group.stream(), span)))
+            b'u' => {
+                let len = position(&text[i..], |b| b == b'}').unwrap();
+                let c = std::char::from_u32(hex(&text[i + 1..i + len])).unwrap();
+                res.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                i += len + 1;
             }
-            _ => tt,
-        };
-        res.extend(Some(tt))
+            // A line continuation skips the newline and leading whitespace.
+            b'\n' => {
+                while i < text.len() && text[i].is_ascii_whitespace() {
+                    i += 1;
+                }
+            }
+            b => res.push(b),
+        }
+    }
+    String::from_utf8(res).unwrap()
+}
+
+/// Parses hex digits, which may be separated by `_`.
+fn hex(digits: &[u8]) -> u32 {
+    let mut res = 0;
+    for &b in digits {
+        if b != b'_' {
+            res = res * 16 + (b as char).to_digit(16).unwrap();
+        }
     }
     res
 }
 
+/// Points every token at `span`, the literal, so that errors in interpolated
+/// expressions are reported there and names resolve at the call site.
+fn respan(ts: TokenStream, span: Span) -> TokenStream {
+    let mut res = TokenStream::new();
+    for mut tt in ts {
+        if let TokenTree::Group(group) = &tt {
+            tt = TokenTree::Group(Group::new(group.delimiter(), respan(group.stream(), span)));
+        }
+        tt.set_span(span);
+        extend(&mut res, TokenStream::from(tt));
+    }
+    res
+}
+
+/// `TokenStream::extend` is generic, funnelling every call through here
+/// instantiates it once.
+fn extend(res: &mut TokenStream, ts: TokenStream) {
+    res.extend(Some(ts))
+}
+
+fn string_arg(text: &str) -> TokenStream {
+    parenthesize(TokenTree::Literal(Literal::string(text)).into())
+}
+
+fn parenthesize(ts: TokenStream) -> TokenStream {
+    TokenTree::Group(Group::new(Delimiter::Parenthesis, ts)).into()
+}
+
 fn parse_ts(s: &str) -> TokenStream {
     s.parse().unwrap()
 }